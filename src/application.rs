@@ -1,43 +1,149 @@
-use std::rc::Rc;
-use std::str::FromStr;
-
 use tokio::sync::mpsc::UnboundedReceiver;
+use tokio::sync::watch;
 
-use crate::cmd::Command;
+use crate::cmd::{Command, ExitStatus};
+use crate::common::ApplicationConfig;
 use crate::config::configuration::Environment;
-use crate::config::Configuration;
 use crate::error::ApplicationError;
 use crate::manager::Manager;
-use crate::services::{Logging, Service};
+use crate::services::{ApiClient, Logging, Service};
+
+/// Loads the environment again when the configuration is reloaded
+pub type Loader = Box<dyn Fn() -> Result<Environment, ApplicationError> + Send>;
 
 pub struct Application {
     services: Vec<Box<dyn Service>>,
     environment: Environment,
+    /// the environment handed to the manager whenever it is reloaded
+    reloaded: watch::Sender<Environment>,
     shutdown: UnboundedReceiver<()>,
+    reload: UnboundedReceiver<()>,
+    loader: Loader,
 }
 
-#[allow(dead_code)]
 impl Application {
-    pub fn new(environment: Environment, shutdown: UnboundedReceiver<()>) -> Self {
-        let logging = Logging::new(environment.logging());
+    pub fn new(
+        environment: Environment,
+        shutdown: UnboundedReceiver<()>,
+        reload: UnboundedReceiver<()>,
+        loader: Loader,
+    ) -> Self {
+        let logging = Logging::new(environment.logging()).file(environment.log_file());
+        let (reloaded, _) = watch::channel(environment.clone());
 
         Self {
             environment,
+            reloaded,
             shutdown,
+            reload,
+            loader,
             services: vec![Box::new(logging)],
         }
     }
 
-    /// start the application for a given command
-    pub async fn run(&self, command: Command) -> Result<(), ApplicationError> {
-        for service in &self.services {
-            service.bootstrap()?;
+    /// start the application for a given command. The command is stopped,
+    /// its remaining output sent and the connection closed when a shutdown is
+    /// requested, the services are left running until `shutdown`.
+    pub async fn run(&mut self, config: ApplicationConfig) -> Result<ExitStatus, ApplicationError> {
+        for service in &mut self.services {
+            service.start()?;
+            debug!("started the {} service", service.name());
+        }
+        let token = self.environment.token()?;
+        let client = ApiClient::new(&self.environment.api_url(), token.expose())?;
+        let command = client
+            .create_command(config.name(), config.tags())
+            .await?
+            .ok_or_else(|| {
+                ApplicationError::transport(format!(
+                    "unable to register the command '{}'",
+                    config.name()
+                ))
+            })?;
+        let session = client
+            .create_session(&command)
+            .await?
+            .ok_or_else(|| ApplicationError::transport("unable to create a session"))?;
+        info!(
+            "created session {} for command {}",
+            session.id(),
+            command.id()
+        );
+
+        let mut command = Command::new(config.executable())
+            .args(config.arguments())
+            .capture(config.capture_mode())
+            .envs(config.env().clone());
+        if let Some(directory) = config.working_directory() {
+            command = command.current_dir(directory);
+        }
+        if let Some(size) = config.window_size() {
+            command = command.tty(size);
+        }
+        if let Some(timeout) = config.max_duration() {
+            command = command.timeout(timeout);
+        }
+        if let Some(timeout) = config.max_idle() {
+            command = command.idle_timeout(timeout);
+        }
+        let manager = Manager::new(self.reloaded.subscribe());
+        let spawned = manager.spawn(&session, command, &mut self.shutdown);
+        tokio::pin!(spawned);
+        loop {
+            tokio::select! {
+                status = &mut spawned => return status,
+                Some(()) = self.reload.recv() => {
+                    Self::reload(&self.loader, &mut self.environment, &mut self.services);
+                    // nobody is left to tell once the manager returned
+                    let _ = self.reloaded.send(self.environment.clone());
+                }
+            }
+        }
+    }
+
+    /// applies the settings of the reloaded configuration which can change
+    /// while the command runs, and reports the others. The running
+    /// configuration is left untouched when the new one is invalid.
+    fn reload(loader: &Loader, environment: &mut Environment, services: &mut [Box<dyn Service>]) {
+        let reloaded = match loader().and_then(|reloaded| reloaded.validate().map(|_| reloaded)) {
+            Ok(reloaded) => reloaded,
+            Err(error) => {
+                error!("keeping the current configuration: {}", error);
+                return;
+            }
+        };
+        for service in services.iter_mut() {
+            if let Err(error) = service.reload(&reloaded) {
+                error!("unable to reload the {} service: {}", service.name(), error);
+            }
         }
-        let manager = Manager::new(&self.environment);
-        manager.spawn(command).await
+        let restart = environment.restart_required(&reloaded);
+        if !restart.is_empty() {
+            warn!(
+                "changes to {} take effect once tracer is restarted",
+                restart.join(", ")
+            );
+        }
+        if environment.token_changed(&reloaded) {
+            // the token is only used to create the session, the websocket
+            // authenticates with the token the session was created with
+            warn!("the running command keeps the token of its session, the new token is used for the next one");
+        }
+        environment.apply(&reloaded);
+        info!("configuration reloaded");
     }
 
-    pub async fn shutdown(&self) -> Result<(), ApplicationError> {
-        todo!()
+    /// stop the services in the reverse order they were started, stopping
+    /// them all even when one of them fails
+    pub async fn shutdown(&mut self) -> Result<(), ApplicationError> {
+        let mut result = Ok(());
+        for service in self.services.iter_mut().rev() {
+            debug!("stopping the {} service", service.name());
+            if let Err(error) = service.stop() {
+                error!("unable to stop the {} service: {}", service.name(), error);
+                result = result.and(Err(error));
+            }
+        }
+        result
     }
 }
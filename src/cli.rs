@@ -1,40 +1,389 @@
-use crate::common::ApplicationConfig;
-use clap::{crate_authors, crate_description, crate_name, crate_version, App, Arg};
+use std::path::PathBuf;
+use std::time::Duration;
+
+use clap::{crate_authors, crate_description, crate_name, crate_version, AppSettings, Parser};
 
+use crate::cmd::{CaptureMode, WindowSize};
+use crate::common::ApplicationConfig;
+use crate::config::configuration::NamedCommand;
+use crate::config::Configuration;
 use crate::error::ApplicationError;
 
-#[allow(dead_code)]
-pub fn parse() -> Result<ApplicationConfig, ApplicationError> {
-    let matches = App::new(crate_name!())
-        .version(crate_version!())
-        .author(crate_authors!())
-        .about(crate_description!())
-        .setting(clap::AppSettings::TrailingVarArg)
-        .arg(
-            Arg::new("name")
-                .short('u')
-                .long("name")
-                .help("A unique command name")
-                .required(true)
-                .takes_value(true),
-        )
-        .arg(
-            Arg::new("command")
-                .help("The command to run")
-                .required(true)
-                .takes_value(true)
-                .allow_hyphen_values(true)
-                .multiple_values(true)
-                .multiple_occurrences(true)
-                .help_heading("command"),
+#[derive(Parser, Debug)]
+#[clap(
+    name = crate_name!(),
+    version = crate_version!(),
+    author = crate_authors!(),
+    about = crate_description!()
+)]
+pub struct Cli {
+    /// Path to the configuration file to use instead of searching for one
+    #[clap(short, long, global = true)]
+    pub config: Option<PathBuf>,
+    /// The profile of the configuration file to use instead of the default one
+    #[clap(short, long, global = true)]
+    pub profile: Option<String>,
+    /// Overrides the logging level of the configuration (e.g. DEBUG, INFO)
+    #[clap(long, global = true)]
+    pub log_level: Option<String>,
+    /// Overrides a setting of the configuration, e.g. reconnect.max_delay=60
+    #[clap(long, global = true, value_name = "KEY=VALUE", parse(try_from_str = parse_setting), multiple_occurrences = true)]
+    pub set: Vec<(String, String)>,
+    #[clap(subcommand)]
+    pub action: Action,
+}
+
+#[derive(clap::Subcommand, Debug)]
+pub enum Action {
+    /// Run a command and send its output to the server
+    #[clap(setting = AppSettings::TrailingVarArg)]
+    Run(RunOptions),
+    /// Inspect the configuration
+    #[clap(subcommand)]
+    Config(ConfigAction),
+    /// Create the configuration file, prompting for what is not given
+    Init(InitOptions),
+}
+
+#[derive(clap::Subcommand, Debug)]
+pub enum ConfigAction {
+    /// Print the effective configuration and where each setting comes from
+    Show,
+    /// List the profiles of the configuration file
+    Profiles,
+    /// Check the configuration, reporting every problem found in it
+    Validate,
+}
+
+#[derive(clap::Args, Debug)]
+pub struct InitOptions {
+    /// The host and port of the server, e.g. tracer.example.com:8080
+    #[clap(long)]
+    pub host: Option<String>,
+    /// Connect to the server over https
+    #[clap(long)]
+    pub https: bool,
+    /// The API token
+    #[clap(long, group = "token_source")]
+    pub token: Option<String>,
+    /// A file holding the API token
+    #[clap(long, group = "token_source", value_name = "PATH")]
+    pub token_file: Option<PathBuf>,
+    /// An environment variable holding the API token
+    #[clap(long, group = "token_source", value_name = "VARIABLE")]
+    pub token_env: Option<String>,
+    /// A command printing the API token
+    #[clap(long, group = "token_source", value_name = "COMMAND")]
+    pub token_command: Option<String>,
+    /// Overwrite the configuration file when it already exists
+    #[clap(short, long)]
+    pub force: bool,
+    /// Write the configuration without checking the server accepts the token
+    #[clap(long)]
+    pub no_verify: bool,
+}
+
+/// Splits a KEY=VALUE setting
+fn parse_setting(setting: &str) -> Result<(String, String), String> {
+    setting
+        .split_once('=')
+        .map(|(key, value)| (key.trim().to_string(), value.to_string()))
+        .ok_or_else(|| format!("expected KEY=VALUE, got {}", setting))
+}
+
+#[derive(clap::Args, Debug)]
+pub struct RunOptions {
+    /// A unique command name, the first word of the command is the name of a
+    /// command defined in the configuration otherwise
+    #[clap(short, long)]
+    pub name: Option<String>,
+    /// Run the command under a pseudo-terminal, merging stdout and stderr
+    #[clap(short, long)]
+    pub tty: bool,
+    /// The size of the pseudo-terminal as <COLUMNS>x<ROWS>
+    #[clap(long, default_value = "80x24")]
+    pub window_size: WindowSize,
+    /// Send the output line by line (the default), or in chunks as soon as it
+    /// is written
+    #[clap(long, possible_values = ["lines", "chunks"])]
+    pub capture: Option<CaptureMode>,
+    /// Stop the command when it runs for longer than this many seconds
+    #[clap(long, value_name = "SECONDS")]
+    pub timeout: Option<u64>,
+    /// Stop the command when it writes no output for this many seconds
+    #[clap(long, value_name = "SECONDS")]
+    pub idle_timeout: Option<u64>,
+    /// The command to run followed by its arguments, or the name of a command
+    /// defined in the configuration followed by arguments extending its own
+    #[clap(multiple_values = true, value_name = "COMMAND")]
+    pub command: Vec<String>,
+}
+
+impl RunOptions {
+    /// Resolves what to run, from the command line and the command defined
+    /// in the configuration under the same name
+    pub fn config(
+        self,
+        configuration: &Configuration,
+    ) -> Result<ApplicationConfig, ApplicationError> {
+        let mut words = self.command.into_iter().peekable();
+        let by_word = self.name.is_none();
+        let name = match self.name.or_else(|| words.next()) {
+            Some(name) => name,
+            None => return Err(ApplicationError::configuration(
+                "give the name of a command defined in the configuration, or --name and a command",
+            )),
+        };
+        // the separator is kept when it follows the name of a defined command
+        words.next_if(|word| by_word && word == "--");
+        let defined = configuration.command(&name);
+        // the definition only says what to run when chosen by name, the
+        // command given with --name takes precedence over it
+        let definition = defined
+            .filter(|_| by_word)
+            .and_then(|defined| defined.executable().map(|executable| (defined, executable)));
+        let config = match definition {
+            Some((defined, executable)) => ApplicationConfig::new(executable, &name)
+                .args(defined.args())
+                .args(words),
+            None if by_word => {
+                return Err(ApplicationError::configuration(format!(
+                    "no command named {} in the configuration, give --name to run another command",
+                    name
+                )))
+            }
+            None => {
+                let executable = words.next().ok_or_else(|| {
+                    ApplicationError::configuration(format!("no command to run as {}", name))
+                })?;
+                ApplicationConfig::new(&executable, &name).args(words)
+            }
+        };
+        let capture = self
+            .capture
+            .or_else(|| defined.and_then(NamedCommand::capture))
+            .unwrap_or_default();
+        let mut config = config
+            .capture(capture)
+            .timeout(self.timeout.map(Duration::from_secs))
+            .idle_timeout(self.idle_timeout.map(Duration::from_secs));
+        if self.tty || defined.is_some_and(NamedCommand::tty) {
+            config = config.tty(self.window_size);
+        }
+        Ok(match defined {
+            Some(defined) => config.defaults(defined),
+            None => config,
+        })
+    }
+}
+
+pub fn parse() -> Cli {
+    Cli::parse()
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn configuration() -> Configuration {
+        Configuration::from_str(
+            r#"
+                environment:
+                    host: localhost:8080
+                    token: a super long token
+                commands:
+                    nightly-backup:
+                        executable: restic
+                        args: [backup, /srv]
+                        tty: true
+                        tags: [nightly]
+                    lint:
+                        timeout: 600
+            "#,
         )
-        .get_matches();
-    let name = matches.value_of("name").unwrap_or("jumping_jacks");
-    if let Some(command) = matches.values_of("command") {
-        let arguments: Vec<&str> = command.collect();
-        let executable = arguments.join(" ");
-        info!("executable: {}", executable);
-        return Ok(ApplicationConfig::new(executable.as_str(), name));
-    }
-    Err(ApplicationError::command("Could not parse command"))
+        .unwrap()
+    }
+
+    #[test]
+    fn test_run_keeps_argument_boundaries() {
+        let cli = Cli::try_parse_from([
+            "tracer",
+            "run",
+            "--name",
+            "build",
+            "--",
+            "sh",
+            "-c",
+            "echo a  b",
+            "--all",
+        ])
+        .unwrap();
+        let options = match cli.action {
+            Action::Run(options) => options,
+            action => panic!("unexpected action {:?}", action),
+        };
+        let config = options.config(&configuration()).unwrap();
+
+        assert_eq!(config.name(), "build");
+        assert_eq!(config.executable(), "sh");
+        assert_eq!(config.arguments(), ["-c", "echo a  b", "--all"]);
+        assert_eq!(config.window_size(), None);
+        assert_eq!(config.capture_mode(), CaptureMode::Lines);
+        assert_eq!(config.max_duration(), None);
+        assert_eq!(config.max_idle(), None);
+    }
+
+    #[test]
+    fn test_run_with_tty() {
+        let cli = Cli::try_parse_from([
+            "tracer",
+            "run",
+            "-n",
+            "test",
+            "--tty",
+            "--window-size",
+            "120x40",
+            "cargo",
+            "test",
+        ])
+        .unwrap();
+        let options = match cli.action {
+            Action::Run(options) => options,
+            action => panic!("unexpected action {:?}", action),
+        };
+        let config = options.config(&configuration()).unwrap();
+
+        assert_eq!(config.window_size(), Some(WindowSize::new(120, 40)));
+    }
+
+    #[test]
+    fn test_run_with_timeouts() {
+        let cli = Cli::try_parse_from([
+            "tracer",
+            "run",
+            "-n",
+            "backup",
+            "--timeout",
+            "3600",
+            "--idle-timeout",
+            "300",
+            "backup.sh",
+        ])
+        .unwrap();
+        let options = match cli.action {
+            Action::Run(options) => options,
+            action => panic!("unexpected action {:?}", action),
+        };
+        let config = options.config(&configuration()).unwrap();
+
+        assert_eq!(config.max_duration(), Some(Duration::from_secs(3600)));
+        assert_eq!(config.max_idle(), Some(Duration::from_secs(300)));
+    }
+
+    #[test]
+    fn test_overrides() {
+        let cli = Cli::try_parse_from([
+            "tracer",
+            "--set",
+            "https=true",
+            "--set",
+            "reconnect.max_delay=60",
+            "config",
+            "show",
+        ])
+        .unwrap();
+
+        assert!(matches!(cli.action, Action::Config(ConfigAction::Show)));
+        assert_eq!(
+            cli.set,
+            [
+                ("https".to_string(), "true".to_string()),
+                ("reconnect.max_delay".to_string(), "60".to_string())
+            ]
+        );
+        assert!(Cli::try_parse_from(["tracer", "--set", "https", "config", "show"]).is_err());
+    }
+
+    #[test]
+    fn test_run_defined_command() {
+        let cli =
+            Cli::try_parse_from(["tracer", "run", "nightly-backup", "--", "--dry-run"]).unwrap();
+        let options = match cli.action {
+            Action::Run(options) => options,
+            action => panic!("unexpected action {:?}", action),
+        };
+        let config = options.config(&configuration()).unwrap();
+
+        assert_eq!(config.name(), "nightly-backup");
+        assert_eq!(config.executable(), "restic");
+        assert_eq!(config.arguments(), ["backup", "/srv", "--dry-run"]);
+        assert_eq!(config.window_size(), Some(WindowSize::new(80, 24)));
+        assert_eq!(config.tags(), ["nightly"]);
+    }
+
+    #[test]
+    fn test_run_named_command_line_takes_precedence() {
+        let cli = Cli::try_parse_from([
+            "tracer",
+            "run",
+            "--name",
+            "nightly-backup",
+            "--",
+            "restic",
+            "check",
+        ])
+        .unwrap();
+        let options = match cli.action {
+            Action::Run(options) => options,
+            action => panic!("unexpected action {:?}", action),
+        };
+        let config = options.config(&configuration()).unwrap();
+
+        assert_eq!(config.name(), "nightly-backup");
+        assert_eq!(config.executable(), "restic");
+        assert_eq!(config.arguments(), ["check"]);
+        assert_eq!(config.tags(), ["nightly"]);
+    }
+
+    #[test]
+    fn test_run_undefined_command() {
+        let cli = Cli::try_parse_from(["tracer", "run", "cargo", "test"]).unwrap();
+        let options = match cli.action {
+            Action::Run(options) => options,
+            action => panic!("unexpected action {:?}", action),
+        };
+
+        assert!(options.config(&configuration()).is_err());
+    }
+
+    #[test]
+    fn test_init_single_token_source() {
+        let cli = Cli::try_parse_from([
+            "tracer",
+            "init",
+            "--host",
+            "localhost:8080",
+            "--token-file",
+            "/etc/tracer/token",
+        ])
+        .unwrap();
+        let conflicting = Cli::try_parse_from([
+            "tracer",
+            "init",
+            "--token",
+            "a super long token",
+            "--token-env",
+            "TRACER_API_TOKEN",
+        ]);
+
+        match cli.action {
+            Action::Init(options) => {
+                assert_eq!(options.host.as_deref(), Some("localhost:8080"));
+                assert_eq!(options.token_file, Some(PathBuf::from("/etc/tracer/token")));
+                assert!(!options.force);
+            }
+            action => panic!("unexpected action {:?}", action),
+        }
+        assert!(conflicting.is_err());
+    }
 }
@@ -0,0 +1,199 @@
+use std::fmt;
+use std::str::FromStr;
+
+use serde::{Deserialize, Serialize};
+
+/// The most bytes of a line held back, a longer line is sent in parts of this
+/// size rather than kept in memory until it ends
+const MAX_PENDING: usize = 64 * 1024;
+
+/// How the bytes written by a command are split into events. Either way the
+/// bytes are kept exactly as written, so that concatenating the events gives
+/// back the original output.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum CaptureMode {
+    /// one event per line, including its terminator. A carriage return not
+    /// followed by a line feed ends a line too, so that each update of a
+    /// progress bar is sent on its own. Overlong lines are split.
+    #[default]
+    Lines,
+    /// the bytes as they are read, without waiting for the end of a line
+    Chunks,
+}
+
+impl fmt::Display for CaptureMode {
+    fn fmt(&self, formatter: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Lines => write!(formatter, "lines"),
+            Self::Chunks => write!(formatter, "chunks"),
+        }
+    }
+}
+
+impl FromStr for CaptureMode {
+    type Err = String;
+
+    fn from_str(value: &str) -> Result<Self, Self::Err> {
+        match value {
+            "lines" => Ok(Self::Lines),
+            "chunks" => Ok(Self::Chunks),
+            _ => Err(format!(
+                "invalid capture mode '{}', expected lines or chunks",
+                value
+            )),
+        }
+    }
+}
+
+/// Splits the bytes read from a command into messages according to the
+/// capture mode, holding back what may still be continued by the next read.
+#[derive(Debug)]
+pub struct Capture {
+    mode: CaptureMode,
+    pending: Vec<u8>,
+}
+
+impl Capture {
+    pub fn new(mode: CaptureMode) -> Self {
+        Self {
+            mode,
+            pending: Vec::new(),
+        }
+    }
+
+    /// whether some bytes are held back waiting for more output
+    pub fn is_pending(&self) -> bool {
+        !self.pending.is_empty()
+    }
+
+    /// Feeds the bytes read, returning the messages they complete
+    pub fn push(&mut self, data: &[u8]) -> Vec<Vec<u8>> {
+        self.pending.extend_from_slice(data);
+        let complete = match self.mode {
+            CaptureMode::Lines => self.split_lines(),
+            CaptureMode::Chunks => self.split_chunk(),
+        };
+        let mut messages = Vec::with_capacity(complete.len());
+        let mut start = 0;
+        for end in complete {
+            messages.push(self.pending[start..end].to_vec());
+            start = end;
+        }
+        self.pending.drain(..start);
+        messages
+    }
+
+    /// Takes whatever is held back, i.e. once the flush timeout elapsed or the
+    /// command closed its output
+    pub fn flush(&mut self) -> Option<Vec<u8>> {
+        if self.pending.is_empty() {
+            return None;
+        }
+        Some(std::mem::take(&mut self.pending))
+    }
+
+    /// the end offsets of the complete lines. A trailing carriage return is
+    /// held back since it may be the start of a `\r\n`, and a line is cut
+    /// once it reaches `MAX_PENDING` bytes, between two characters if it can.
+    fn split_lines(&self) -> Vec<usize> {
+        let mut ends = Vec::new();
+        let mut start = 0;
+        for (index, byte) in self.pending.iter().enumerate() {
+            let end = match byte {
+                b'\n' => index + 1,
+                b'\r' => match self.pending.get(index + 1) {
+                    Some(b'\n') | None => continue,
+                    Some(_) => index + 1,
+                },
+                _ if index + 1 - start >= MAX_PENDING => {
+                    match std::str::from_utf8(&self.pending[start..=index]) {
+                        Err(error) if error.error_len().is_none() && error.valid_up_to() > 0 => {
+                            start + error.valid_up_to()
+                        }
+                        _ => index + 1,
+                    }
+                }
+                _ => continue,
+            };
+            ends.push(end);
+            start = end;
+        }
+        ends
+    }
+
+    /// everything but an incomplete UTF-8 sequence at the end, which the next
+    /// read will most likely complete
+    fn split_chunk(&self) -> Vec<usize> {
+        let end = match std::str::from_utf8(&self.pending) {
+            Err(error) if error.error_len().is_none() => error.valid_up_to(),
+            _ => self.pending.len(),
+        };
+        if end == 0 {
+            return vec![];
+        }
+        vec![end]
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn strings(messages: Vec<Vec<u8>>) -> Vec<String> {
+        messages
+            .into_iter()
+            .map(|message| String::from_utf8(message).unwrap())
+            .collect()
+    }
+
+    #[test]
+    fn test_lines_keep_terminators() {
+        let mut capture = Capture::new(CaptureMode::Lines);
+        assert_eq!(
+            strings(capture.push(b"one\ntwo\r\nthr")),
+            ["one\n", "two\r\n"]
+        );
+        assert!(capture.is_pending());
+        assert_eq!(strings(capture.push(b"ee\n")), ["three\n"]);
+        assert!(capture.flush().is_none());
+    }
+
+    #[test]
+    fn test_lines_split_on_carriage_return() {
+        let mut capture = Capture::new(CaptureMode::Lines);
+        assert_eq!(strings(capture.push(b"10%\r20%\r")), ["10%\r"]);
+        assert_eq!(strings(capture.push(b"30%\n")), ["20%\r", "30%\n"]);
+    }
+
+    #[test]
+    fn test_lines_keep_invalid_utf8() {
+        let mut capture = Capture::new(CaptureMode::Lines);
+        assert_eq!(capture.push(b"\xff\xfe\n"), [b"\xff\xfe\n".to_vec()]);
+        capture.push(b"partial");
+        assert_eq!(capture.flush(), Some(b"partial".to_vec()));
+    }
+
+    #[test]
+    fn test_lines_split_when_too_long() {
+        let mut capture = Capture::new(CaptureMode::Lines);
+        let mut data = vec![b'a'; MAX_PENDING - 1];
+        // "é" is encoded as 0xc3 0xa9, and is not cut in half
+        data.extend_from_slice(b"\xc3\xa9");
+        assert!(capture.push(&data[..10]).is_empty());
+        let messages = capture.push(&data[10..MAX_PENDING]);
+        assert_eq!(messages, [vec![b'a'; MAX_PENDING - 1]]);
+        assert!(capture.push(&data[MAX_PENDING..]).is_empty());
+        assert_eq!(capture.flush(), Some(b"\xc3\xa9".to_vec()));
+    }
+
+    #[test]
+    fn test_chunks_hold_back_incomplete_characters() {
+        let mut capture = Capture::new(CaptureMode::Chunks);
+        // "é" is encoded as 0xc3 0xa9
+        assert_eq!(capture.push(b"caf\xc3"), [b"caf".to_vec()]);
+        assert_eq!(capture.push(b"\xa9 "), [b"\xc3\xa9 ".to_vec()]);
+        assert_eq!(capture.push(b"\xff"), [b"\xff".to_vec()]);
+        assert!(!capture.is_pending());
+    }
+}
@@ -1,36 +1,84 @@
-use std::io::Write;
+use std::io;
+use std::sync::mpsc::Sender;
 use std::sync::Arc;
 
-use os_pipe::PipeWriter;
 use shared_child::SharedChild;
 
+use crate::cmd::pty::{Pty, WindowSize};
+use crate::cmd::Signal;
 use crate::error::ApplicationError;
 
-#[derive(Debug)]
 pub struct CommandChild {
     inner: Arc<SharedChild>,
-    stdin_writer: PipeWriter,
+    stdin: Option<Sender<Vec<u8>>>,
+    pty: Option<Pty>,
 }
 
 impl CommandChild {
-    pub fn new(inner: Arc<SharedChild>, stdin_writer: PipeWriter) -> Self {
+    pub fn new(inner: Arc<SharedChild>, stdin: Sender<Vec<u8>>) -> Self {
         Self {
             inner,
-            stdin_writer,
+            stdin: Some(stdin),
+            pty: None,
         }
     }
-    /// Writes to process stdin.
-    pub fn write(&mut self, buf: &[u8]) -> Result<(), ApplicationError> {
-        self.stdin_writer.write_all(buf)?;
-        Ok(())
+
+    /// Attaches the pseudo-terminal the process runs under.
+    pub fn with_pty(mut self, pty: Pty) -> Self {
+        self.pty = Some(pty);
+        self
+    }
+
+    /// Writes to process stdin, without waiting for the process to read it.
+    pub fn write(&self, buf: &[u8]) -> Result<(), ApplicationError> {
+        let stdin = self
+            .stdin
+            .as_ref()
+            .ok_or_else(|| ApplicationError::command("the standard input is closed"))?;
+        stdin
+            .send(buf.to_vec())
+            .map_err(|_| ApplicationError::command("the process no longer reads its input"))
+    }
+
+    /// Closes process stdin once everything written was delivered.
+    pub fn close_stdin(&mut self) {
+        self.stdin = None;
     }
 
-    /// Sends a kill signal to the child.
-    pub fn kill(self) -> Result<(), ApplicationError> {
-        self.inner.kill()?;
+    /// Resizes the terminal of the process, failing when it has none.
+    pub fn resize(&self, size: WindowSize) -> Result<(), ApplicationError> {
+        match &self.pty {
+            Some(pty) => pty.resize(size),
+            None => Err(ApplicationError::command(
+                "the command is not running in a terminal",
+            )),
+        }
+    }
+
+    /// Sends a signal to the process group of the child, i.e. the child and
+    /// everything it spawned that did not move to another group.
+    pub fn signal(&self, signal: Signal) -> Result<(), ApplicationError> {
+        let group = -(self.pid() as i32);
+        if unsafe { libc::kill(group, signal.number()) } == -1 {
+            let error = io::Error::last_os_error();
+            // the group is already gone
+            if error.raw_os_error() != Some(libc::ESRCH) {
+                return Err(ApplicationError::command(format!(
+                    "unable to send {} to {}: {}",
+                    signal,
+                    self.pid(),
+                    error
+                )));
+            }
+        }
         Ok(())
     }
 
+    /// Sends a kill signal to the process group of the child.
+    pub fn kill(&self) -> Result<(), ApplicationError> {
+        self.signal(Signal::KILL)
+    }
+
     /// Returns the process pid.
     pub fn pid(&self) -> u32 {
         self.inner.id()
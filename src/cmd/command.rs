@@ -1,92 +1,270 @@
-use std::os::unix::prelude::ExitStatusExt;
+use std::os::unix::prelude::{CommandExt, ExitStatusExt};
 use std::{
     collections::HashMap,
-    io::{BufRead, BufReader, Write},
+    fmt,
+    io::{self, ErrorKind, Read, Write},
+    os::unix::io::AsRawFd,
+    path::PathBuf,
     process::{Command as StdCommand, Stdio},
-    sync::Arc,
+    sync::{mpsc, mpsc::Receiver, Arc},
+    thread::{self, JoinHandle},
+    time::{Duration, Instant},
 };
 
-use os_pipe::{pipe, PipeReader, PipeWriter};
+use os_pipe::pipe;
 use shared_child::SharedChild;
 use tokio::sync::mpsc::error::SendError;
-use tokio::sync::mpsc::{Receiver, Sender};
-use tokio::sync::{mpsc, RwLock};
+use tokio::sync::mpsc::Sender;
 
+use crate::cmd::capture::{Capture, CaptureMode};
 use crate::cmd::child::CommandChild;
-use crate::cmd::event::CommandEvent;
+use crate::cmd::event::{CommandEvent, Timestamp};
+use crate::cmd::pty::{make_controlling_terminal, Pty, WindowSize};
+use crate::cmd::reaper::{self, Process};
 use crate::error::ApplicationError;
 
+/// The terminal type advertised to commands running under a pseudo-terminal
+const DEFAULT_TERM: &str = "xterm-256color";
+
+/// The character ending the input of a terminal, i.e. ctrl-d
+const TERMINAL_EOF: u8 = 0x04;
+
+/// How many bytes are read from a pipe or pseudo-terminal at once
+const READ_SIZE: usize = 8192;
+
+/// The stack size of the threads handling a process, they only need room
+/// for a read buffer so that many commands can run side by side
+const HANDLER_STACK_SIZE: usize = 128 * 1024;
+
+/// How long the output of a command is drained after it exited
+const DRAIN_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// How often the output handlers are checked while draining
+const DRAIN_POLL_INTERVAL: Duration = Duration::from_millis(10);
+
+/// How long bytes that do not end a line or character are held back before
+/// they are published anyway, e.g. a prompt waiting for input
+const FLUSH_TIMEOUT: Duration = Duration::from_millis(200);
+
 macro_rules! get_std_command {
     ($self: ident) => {{
-        let mut command = StdCommand::new($self.executable);
+        let mut command = StdCommand::new(&$self.executable);
         command.args(&$self.args);
         command.stdout(Stdio::piped());
         command.stdin(Stdio::piped());
         command.stderr(Stdio::piped());
-        command.envs($self.env);
+        command.envs(&$self.env);
+        if let Some(directory) = &$self.directory {
+            command.current_dir(directory);
+        }
         #[cfg(windows)]
         command.creation_flags(CREATE_NO_WINDOW);
         command
     }};
 }
 
-async fn handle_stdout(reader: PipeReader, lock: Arc<RwLock<()>>, tx: Sender<CommandEvent>) {
-    lock.read().await;
-    let reader = BufReader::new(reader);
-    for line in reader.lines() {
-        match line {
-            Ok(line) => {
-                tx.send(CommandEvent::Stdout(line)).await;
+/// Waits until there is something to read, returning false on timeout
+fn wait_readable(reader: &impl AsRawFd, timeout: Duration) -> bool {
+    let mut poll = libc::pollfd {
+        fd: reader.as_raw_fd(),
+        events: libc::POLLIN,
+        revents: 0,
+    };
+    let timeout = timeout.as_millis().min(i32::MAX as u128) as i32;
+    // errors are left to the following read to report
+    unsafe { libc::poll(&mut poll, 1, timeout) != 0 }
+}
+
+/// Reads the bytes written on a pipe or terminal and publishes them as they
+/// are captured, timestamping them as soon as they are read. Bytes held back
+/// by the capture are published anyway once the flush timeout elapsed.
+fn handle_output<R: Read + AsRawFd>(
+    mut reader: R,
+    mode: CaptureMode,
+    launched: Instant,
+    tx: Sender<CommandEvent>,
+    event: fn(Vec<u8>, Timestamp) -> CommandEvent,
+) {
+    let mut capture = Capture::new(mode);
+    let mut buffer = [0; READ_SIZE];
+    // when the bytes held back by the capture were read
+    let mut pending_since = Timestamp::since(launched);
+    loop {
+        if capture.is_pending() && !wait_readable(&reader, FLUSH_TIMEOUT) {
+            if let Some(message) = capture.flush() {
+                if tx.blocking_send(event(message, pending_since)).is_err() {
+                    return;
+                }
             }
+            continue;
+        }
+        let read = match reader.read(&mut buffer) {
+            Ok(0) => break,
+            Ok(read) => read,
+            Err(error) if error.kind() == ErrorKind::Interrupted => continue,
+            // linux reports EIO once every process closed a terminal
+            Err(error) if error.raw_os_error() == Some(libc::EIO) => break,
             Err(error) => {
-                tx.send(CommandEvent::Error(error.to_string())).await;
+                let _ = tx.blocking_send(CommandEvent::Error {
+                    message: error.to_string(),
+                    timestamp: Timestamp::since(launched),
+                });
+                break;
             }
         };
+        let timestamp = Timestamp::since(launched);
+        let held_back = capture.is_pending();
+        let messages = capture.push(&buffer[..read]);
+        // the first message completes the bytes held back, if any
+        let mut captured = if held_back { pending_since } else { timestamp };
+        if !messages.is_empty() || !held_back {
+            pending_since = timestamp;
+        }
+        for message in messages {
+            if tx.blocking_send(event(message, captured)).is_err() {
+                return;
+            }
+            captured = timestamp;
+        }
+    }
+    if let Some(message) = capture.flush() {
+        let _ = tx.blocking_send(event(message, pending_since));
     }
 }
 
-async fn handle_stderr(reader: PipeReader, lock: Arc<RwLock<()>>, tx: Sender<CommandEvent>) {
-    lock.read().await;
-    let reader = BufReader::new(reader);
-    for line in reader.lines() {
-        match line {
-            Ok(line) => {
-                tx.send(CommandEvent::Stderr(line)).await;
-            }
-            Err(error) => {
-                tx.send(CommandEvent::Error(error.to_string())).await;
-            }
+/// Writes what is received to the standard input of the process until the
+/// sender is dropped, then signals the end of the input: closing a pipe is
+/// enough, a terminal needs its end-of-file character instead.
+fn handle_input(mut writer: impl Write, input: Receiver<Vec<u8>>, eof: Option<u8>) {
+    let mut line_started = false;
+    for data in input {
+        if let Err(error) = writer.write_all(&data).and_then(|_| writer.flush()) {
+            debug!("unable to write to the standard input: {}", error);
+            return;
+        }
+        if let Some(last) = data.last() {
+            line_started = *last != b'\n';
+        }
+    }
+    if let Some(eof) = eof {
+        // a terminal only reports the end of the input on an empty line, the
+        // first end-of-file character merely submits a line being typed
+        let eof = if line_started {
+            vec![eof; 2]
+        } else {
+            vec![eof]
         };
+        if let Err(error) = writer.write_all(&eof) {
+            debug!("unable to close the standard input: {}", error);
+        }
     }
 }
 
-async fn handle_exit(
+/// Spawns a thread to run a handler of the process with the given pid. The
+/// handlers block on the pipes and on the child, which would stall the
+/// websocket if they ran on the runtime worker threads.
+fn spawn_handler<F, T>(name: &str, pid: u32, handler: F) -> Result<JoinHandle<T>, ApplicationError>
+where
+    F: FnOnce() -> T + Send + 'static,
+    T: Send + 'static,
+{
+    thread::Builder::new()
+        .name(format!("{}-{}", name, pid))
+        .stack_size(HANDLER_STACK_SIZE)
+        .spawn(handler)
+        .map_err(|error| {
+            ApplicationError::command(format!(
+                "unable to spawn the {} handler of {}: {}",
+                name, pid, error
+            ))
+        })
+}
+
+fn handle_exit(
     child: Arc<SharedChild>,
-    lock: Arc<RwLock<()>>,
+    launched: Instant,
+    readers: Vec<JoinHandle<()>>,
     tx: Sender<CommandEvent>,
 ) -> Result<(), SendError<CommandEvent>> {
-    match child.wait() {
-        Ok(status) => {
-            lock.write().await;
-            tx.send(CommandEvent::Exited {
-                code: status.code(),
-                signal: status.signal(),
-            })
-            .await
+    let status = child.wait();
+    let timestamp = Timestamp::since(launched);
+    // whatever the command left running may hold the pipes open, so it is
+    // stopped before waiting for the output handlers to drain them
+    let lingering = reaper::reap(child.id());
+    if !lingering.is_empty() {
+        warn!(
+            "stopped {} process(es) left running by the command",
+            lingering.len()
+        );
+    }
+    // wait for the output handlers to drain the pipes so that the exit event
+    // is always the last event sent for a command, unless a process outside
+    // of reach still holds them open
+    let deadline = Instant::now() + DRAIN_TIMEOUT;
+    while readers.iter().any(|reader| !reader.is_finished()) {
+        if Instant::now() >= deadline {
+            warn!("the output of the command is still open, it is no longer read");
+            return report(tx, status, lingering, timestamp);
         }
-        Err(error) => {
-            lock.write().await;
-            tx.send(CommandEvent::Error(error.to_string())).await
+        thread::sleep(DRAIN_POLL_INTERVAL);
+    }
+    for reader in readers {
+        if reader.join().is_err() {
+            error!("an output handler panicked");
         }
     }
+    report(tx, status, lingering, timestamp)
+}
+
+fn report(
+    tx: Sender<CommandEvent>,
+    status: io::Result<std::process::ExitStatus>,
+    lingering: Vec<Process>,
+    timestamp: Timestamp,
+) -> Result<(), SendError<CommandEvent>> {
+    match status {
+        Ok(status) => tx.blocking_send(CommandEvent::Exited {
+            code: status.code(),
+            signal: status.signal(),
+            lingering,
+            timestamp,
+        }),
+        Err(error) => tx.blocking_send(CommandEvent::Error {
+            message: error.to_string(),
+            timestamp,
+        }),
+    }
 }
 
 /// The type to spawn commands.
-#[derive(Debug)]
 pub struct Command {
     executable: String,
     args: Vec<String>,
     env: HashMap<String, String>,
+    directory: Option<PathBuf>,
+    tty: Option<WindowSize>,
+    capture: CaptureMode,
+    timeout: Option<Duration>,
+    idle_timeout: Option<Duration>,
+}
+
+impl fmt::Debug for Command {
+    fn fmt(&self, formatter: &mut fmt::Formatter<'_>) -> fmt::Result {
+        // the variables may hold secrets, only their names are shown
+        let mut env: Vec<&String> = self.env.keys().collect();
+        env.sort();
+        formatter
+            .debug_struct("Command")
+            .field("executable", &self.executable)
+            .field("args", &self.args)
+            .field("env", &env)
+            .field("directory", &self.directory)
+            .field("tty", &self.tty)
+            .field("capture", &self.capture)
+            .field("timeout", &self.timeout)
+            .field("idle_timeout", &self.idle_timeout)
+            .finish()
+    }
 }
 
 impl Command {
@@ -96,6 +274,11 @@ impl Command {
             executable: executable.into(),
             args: Default::default(),
             env: Default::default(),
+            directory: None,
+            tty: None,
+            capture: Default::default(),
+            timeout: None,
+            idle_timeout: None,
         }
     }
 
@@ -113,12 +296,61 @@ impl Command {
 
     /// Adds or updates multiple environment variable mappings.
     pub fn envs(mut self, env: HashMap<String, String>) -> Self {
-        self.env = env;
+        self.env.extend(env);
+        self
+    }
+
+    /// Sets the working directory of the cmd.
+    pub fn current_dir(mut self, directory: impl Into<PathBuf>) -> Self {
+        self.directory = Some(directory.into());
+        self
+    }
+
+    /// Runs the cmd under a pseudo-terminal of the given size rather than
+    /// pipes, its stdout and stderr are then merged into the terminal.
+    pub fn tty(mut self, size: WindowSize) -> Self {
+        self.tty = Some(size);
         self
     }
 
-    /// Spawns the cmd.
-    pub fn spawn(self, bus: Sender<CommandEvent>) -> Result<u32, ApplicationError> {
+    /// Sets how the output of the cmd is split into events.
+    pub fn capture(mut self, mode: CaptureMode) -> Self {
+        self.capture = mode;
+        self
+    }
+
+    /// Stops the cmd when it runs for longer than the given duration.
+    pub fn timeout(mut self, timeout: Duration) -> Self {
+        self.timeout = Some(timeout);
+        self
+    }
+
+    /// Stops the cmd when it writes no output for longer than the given
+    /// duration.
+    pub fn idle_timeout(mut self, timeout: Duration) -> Self {
+        self.idle_timeout = Some(timeout);
+        self
+    }
+
+    /// The maximum run duration of the cmd, if any.
+    pub fn max_duration(&self) -> Option<Duration> {
+        self.timeout
+    }
+
+    /// How long the cmd may go without writing output, if limited.
+    pub fn max_idle(&self) -> Option<Duration> {
+        self.idle_timeout
+    }
+
+    /// Spawns the cmd, publishing its output and exit status on the given bus.
+    pub fn spawn(self, bus: Sender<CommandEvent>) -> Result<CommandChild, ApplicationError> {
+        match self.tty {
+            Some(size) => self.spawn_tty(size, bus),
+            None => self.spawn_piped(bus),
+        }
+    }
+
+    fn spawn_piped(self, bus: Sender<CommandEvent>) -> Result<CommandChild, ApplicationError> {
         let mut command = get_std_command!(self);
         let (stdout_reader, stdout_writer) = pipe()?;
         let (stderr_reader, stderr_writer) = pipe()?;
@@ -127,19 +359,176 @@ impl Command {
         command.stdout(stdout_writer);
         command.stderr(stderr_writer);
         command.stdin(stdin_reader);
+        // lead a process group so that signals reach everything it spawns
+        command.process_group(0);
+
+        let child = self.launch(&mut command)?;
+        let launched = Instant::now();
+
+        // drop our copies of the write ends so the readers see EOF once the
+        // child (and anything it spawned) closes them
+        drop(command);
+
+        // the handlers block on the pipes and on the child, so they run on
+        // their own threads rather than on the runtime worker threads
+        let mode = self.capture;
+        let stdout_bus = bus.clone();
+        let stderr_bus = bus.clone();
+        let exit_child = child.clone();
+        let pid = child.id();
+        let readers = vec![
+            spawn_handler("stdout", pid, move || {
+                handle_output(
+                    stdout_reader,
+                    mode,
+                    launched,
+                    stdout_bus,
+                    |data, timestamp| CommandEvent::Stdout { data, timestamp },
+                )
+            })?,
+            spawn_handler("stderr", pid, move || {
+                handle_output(
+                    stderr_reader,
+                    mode,
+                    launched,
+                    stderr_bus,
+                    |data, timestamp| CommandEvent::Stderr { data, timestamp },
+                )
+            })?,
+        ];
+        spawn_handler("exit", pid, move || {
+            handle_exit(exit_child, launched, readers, bus)
+        })?;
+        let (input, received) = mpsc::channel();
+        spawn_handler("stdin", pid, move || {
+            handle_input(stdin_writer, received, None)
+        })?;
+
+        Ok(CommandChild::new(child, input))
+    }
 
-        let child = SharedChild::spawn(&mut command)?;
-        let child = Arc::new(child);
-        let lock = Arc::new(RwLock::new(()));
+    fn spawn_tty(
+        self,
+        size: WindowSize,
+        bus: Sender<CommandEvent>,
+    ) -> Result<CommandChild, ApplicationError> {
+        let mut command = get_std_command!(self);
+        let (pty, terminal) = Pty::open(size)?;
+        command.stdin(terminal.try_clone()?);
+        command.stdout(terminal.try_clone()?);
+        command.stderr(terminal);
+        if !self.env.contains_key("TERM") {
+            command.env("TERM", DEFAULT_TERM);
+        }
+        unsafe { command.pre_exec(make_controlling_terminal) };
+
+        let child = self.launch(&mut command)?;
+        let launched = Instant::now();
+
+        // the terminal reports EOF once no process holds its other side open
+        drop(command);
+
+        let reader = pty.reader()?;
+        let writer = pty.writer()?;
+        let exit_child = child.clone();
+        let output_bus = bus.clone();
+        let mode = self.capture;
+        let pid = child.id();
+        let readers = vec![spawn_handler("tty", pid, move || {
+            handle_output(reader, mode, launched, output_bus, |data, timestamp| {
+                CommandEvent::Terminal { data, timestamp }
+            })
+        })?];
+        spawn_handler("exit", pid, move || {
+            handle_exit(exit_child, launched, readers, bus)
+        })?;
+        let (input, received) = mpsc::channel();
+        spawn_handler("stdin", pid, move || {
+            handle_input(writer, received, Some(TERMINAL_EOF))
+        })?;
+
+        Ok(CommandChild::new(child, input).with_pty(pty))
+    }
+
+    fn launch(&self, command: &mut StdCommand) -> Result<Arc<SharedChild>, ApplicationError> {
+        reaper::become_subreaper();
+        let parent = std::process::id();
+        // the signal is tied to the thread spawning the command rather than
+        // to tracer, which is fine as commands are spawned from the main
+        // thread: Manager::spawn runs in the future #[tokio::main] blocks on
+        unsafe { command.pre_exec(move || reaper::die_with_parent(parent)) };
+        let child = reaper::spawn(command).map_err(|error| {
+            ApplicationError::command(format!("unable to launch {}: {}", self.executable, error))
+        })?;
+        Ok(Arc::new(child))
+    }
+}
 
-        let stdout_handler = handle_stdout(stdout_reader, lock.clone(), bus.clone());
-        let stderr_handler = handle_stderr(stderr_reader, lock.clone(), bus.clone());
-        let exit_handler = handle_exit(child.clone(), lock, bus.clone());
+#[cfg(test)]
+mod test {
+    use tokio::sync::mpsc::channel;
+    use tokio::time::interval;
 
-        tokio::spawn(stdout_handler);
-        tokio::spawn(stderr_handler);
-        tokio::spawn(exit_handler);
+    use super::*;
+
+    #[test]
+    fn test_debug_masks_the_environment() {
+        let env = HashMap::from([("RESTIC_PASSWORD".to_string(), "hunter2".to_string())]);
+        let command = Command::new("restic").args(["backup"]).envs(env);
+
+        let debug = format!("{:?}", command);
+
+        assert!(debug.contains("RESTIC_PASSWORD"));
+        assert!(!debug.contains("hunter2"));
+    }
+
+    /// many chatty commands and a small bus should not keep the runtime from
+    /// running other tasks, such as the websocket heartbeat
+    #[tokio::test]
+    async fn test_chatty_commands_do_not_stall_the_runtime() {
+        const COMMANDS: usize = 8;
+        const LINES: usize = 5000;
+
+        let (bus, mut events) = channel(16);
+        let children: Vec<CommandChild> = (0..COMMANDS)
+            .map(|_| {
+                Command::new("seq")
+                    .args(["1", &LINES.to_string()])
+                    .spawn(bus.clone())
+                    .unwrap()
+            })
+            .collect();
+        drop(bus);
+
+        let mut ticker = interval(Duration::from_millis(10));
+        let mut last_tick = Instant::now();
+        let mut longest_gap = Duration::ZERO;
+        let mut lines = 0;
+        let mut exited = 0;
+        while exited < children.len() {
+            tokio::select! {
+                _ = ticker.tick() => {
+                    longest_gap = longest_gap.max(last_tick.elapsed());
+                    last_tick = Instant::now();
+                }
+                Some(event) = events.recv() => match event {
+                    CommandEvent::Stdout { .. } => lines += 1,
+                    CommandEvent::Exited { code, .. } => {
+                        assert_eq!(code, Some(0));
+                        exited += 1;
+                    }
+                    event => panic!("unexpected event {:?}", event),
+                }
+            }
+        }
 
-        Ok(child.id())
+        assert_eq!(lines, COMMANDS * LINES);
+        // a stalled runtime would not tick until the commands exited, the
+        // bound is loose so that a busy machine does not fail the test
+        assert!(
+            longest_gap < Duration::from_secs(2),
+            "the runtime stalled for {:?}",
+            longest_gap
+        );
     }
 }
@@ -1,11 +1,70 @@
-use std::fmt;
+use std::time::{Duration, Instant};
 
+use chrono::{DateTime, Utc};
+
+use crate::cmd::Process;
+
+/// When an event was captured: the wall-clock time, and the time elapsed
+/// since the command was launched which is unaffected by clock adjustments.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Timestamp {
+    time: DateTime<Utc>,
+    offset: Duration,
+}
+
+impl Timestamp {
+    /// Captures the current time for a command launched at the given instant
+    pub fn since(launched: Instant) -> Self {
+        Self {
+            time: Utc::now(),
+            offset: launched.elapsed(),
+        }
+    }
+
+    pub fn time(&self) -> DateTime<Utc> {
+        self.time
+    }
+
+    pub fn offset(&self) -> Duration {
+        self.offset
+    }
+}
+
+/// What happened to a command. Output is published as the exact bytes written.
+#[derive(Debug)]
 pub enum CommandEvent {
-    Stderr(String),
-    Stdout(String),
-    Error(String),
+    Stderr {
+        data: Vec<u8>,
+        timestamp: Timestamp,
+    },
+    Stdout {
+        data: Vec<u8>,
+        timestamp: Timestamp,
+    },
+    /// what was written to the pseudo-terminal, stdout and stderr merged
+    Terminal {
+        data: Vec<u8>,
+        timestamp: Timestamp,
+    },
+    Error {
+        message: String,
+        timestamp: Timestamp,
+    },
     Exited {
         code: Option<i32>,
         signal: Option<i32>,
+        /// the processes it left running, which were stopped
+        lingering: Vec<Process>,
+        timestamp: Timestamp,
     },
 }
+
+impl CommandEvent {
+    /// Whether the event carries output of the command
+    pub fn is_output(&self) -> bool {
+        matches!(
+            self,
+            Self::Stderr { .. } | Self::Stdout { .. } | Self::Terminal { .. }
+        )
+    }
+}
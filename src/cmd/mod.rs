@@ -1,5 +1,16 @@
+pub use capture::CaptureMode;
+pub use child::CommandChild;
 pub use command::Command;
-pub use event::CommandEvent;
+pub use event::{CommandEvent, Timestamp};
+pub use pty::WindowSize;
+pub use reaper::{output, Process};
+pub use signal::Signal;
+pub use status::ExitStatus;
+mod capture;
 mod child;
 mod command;
 mod event;
+mod pty;
+mod reaper;
+mod signal;
+mod status;
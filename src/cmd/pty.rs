@@ -0,0 +1,160 @@
+use std::fmt;
+use std::fs::File;
+use std::io;
+use std::os::unix::io::{AsRawFd, FromRawFd};
+use std::ptr;
+use std::str::FromStr;
+
+use serde::{Deserialize, Serialize};
+
+use crate::error::ApplicationError;
+
+/// The size of a terminal window in characters.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub struct WindowSize {
+    pub columns: u16,
+    pub rows: u16,
+}
+
+impl WindowSize {
+    pub fn new(columns: u16, rows: u16) -> Self {
+        Self { columns, rows }
+    }
+
+    fn winsize(&self) -> libc::winsize {
+        libc::winsize {
+            ws_row: self.rows,
+            ws_col: self.columns,
+            ws_xpixel: 0,
+            ws_ypixel: 0,
+        }
+    }
+}
+
+impl Default for WindowSize {
+    fn default() -> Self {
+        Self::new(80, 24)
+    }
+}
+
+impl fmt::Display for WindowSize {
+    fn fmt(&self, formatter: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(formatter, "{}x{}", self.columns, self.rows)
+    }
+}
+
+/// Parses a size written as `<columns>x<rows>`, e.g. `120x40`
+impl FromStr for WindowSize {
+    type Err = String;
+
+    fn from_str(value: &str) -> Result<Self, Self::Err> {
+        let invalid = || format!("invalid window size '{}', expected <columns>x<rows>", value);
+        let (columns, rows) = value.split_once('x').ok_or_else(invalid)?;
+        let columns = columns.trim().parse().map_err(|_| invalid())?;
+        let rows = rows.trim().parse().map_err(|_| invalid())?;
+        if columns == 0 || rows == 0 {
+            return Err(invalid());
+        }
+        Ok(Self::new(columns, rows))
+    }
+}
+
+/// The controller side of a pseudo-terminal, the command runs on the other
+/// side and sees a terminal as its standard streams.
+#[derive(Debug)]
+pub struct Pty {
+    master: File,
+}
+
+impl Pty {
+    /// Opens a pseudo-terminal of the given size, returning it along with the
+    /// side to hand over to the command
+    pub fn open(size: WindowSize) -> Result<(Self, File), ApplicationError> {
+        let mut master = 0;
+        let mut slave = 0;
+        let winsize = size.winsize();
+        let result = unsafe {
+            libc::openpty(
+                &mut master,
+                &mut slave,
+                ptr::null_mut(),
+                ptr::null_mut(),
+                &winsize,
+            )
+        };
+        if result != 0 {
+            return Err(ApplicationError::command(format!(
+                "unable to open a pseudo-terminal: {}",
+                io::Error::last_os_error()
+            )));
+        }
+        let (master, slave) = unsafe { (File::from_raw_fd(master), File::from_raw_fd(slave)) };
+        for file in [&master, &slave] {
+            set_cloexec(file)?;
+        }
+        Ok((Self { master }, slave))
+    }
+
+    /// A handle to read what the command writes to the terminal
+    pub fn reader(&self) -> Result<File, ApplicationError> {
+        Ok(self.master.try_clone()?)
+    }
+
+    /// A handle to type into the terminal
+    pub fn writer(&self) -> Result<File, ApplicationError> {
+        Ok(self.master.try_clone()?)
+    }
+
+    /// Changes the window size, the command is notified with `SIGWINCH`
+    pub fn resize(&self, size: WindowSize) -> Result<(), ApplicationError> {
+        let winsize = size.winsize();
+        let result = unsafe { libc::ioctl(self.master.as_raw_fd(), libc::TIOCSWINSZ, &winsize) };
+        if result != 0 {
+            return Err(ApplicationError::command(format!(
+                "unable to resize the terminal: {}",
+                io::Error::last_os_error()
+            )));
+        }
+        Ok(())
+    }
+}
+
+/// Makes the calling process the leader of a new session with the terminal
+/// on its standard input as controlling terminal. Only meant to be called in
+/// the child between `fork` and `exec`.
+pub fn make_controlling_terminal() -> io::Result<()> {
+    unsafe {
+        if libc::setsid() == -1 {
+            return Err(io::Error::last_os_error());
+        }
+        #[allow(clippy::useless_conversion)]
+        if libc::ioctl(0, libc::TIOCSCTTY.into(), 0) == -1 {
+            return Err(io::Error::last_os_error());
+        }
+    }
+    Ok(())
+}
+
+fn set_cloexec(file: &File) -> Result<(), ApplicationError> {
+    let result = unsafe { libc::fcntl(file.as_raw_fd(), libc::F_SETFD, libc::FD_CLOEXEC) };
+    if result == -1 {
+        return Err(io::Error::last_os_error().into());
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_parse_window_size() {
+        assert_eq!(
+            "120x40".parse::<WindowSize>().unwrap(),
+            WindowSize::new(120, 40)
+        );
+        assert!("120".parse::<WindowSize>().is_err());
+        assert!("0x40".parse::<WindowSize>().is_err());
+        assert!("ax40".parse::<WindowSize>().is_err());
+    }
+}
@@ -0,0 +1,268 @@
+use std::collections::HashSet;
+use std::io;
+use std::process::{Output, Stdio};
+use std::sync::Mutex;
+use std::thread;
+use std::time::{Duration, Instant};
+
+use serde::{Deserialize, Serialize};
+use shared_child::SharedChild;
+
+/// How long lingering processes are given to exit after SIGTERM before they
+/// are killed
+const LINGER_GRACE_PERIOD: Duration = Duration::from_secs(2);
+
+/// How often lingering processes are checked while waiting for them to exit
+const LINGER_POLL_INTERVAL: Duration = Duration::from_millis(50);
+
+/// The children tracer started which are running, any other process tracer
+/// adopted as a subreaper was left behind by one of them
+static COMMANDS: Mutex<Option<HashSet<u32>>> = Mutex::new(None);
+
+/// A process left running by a command after it exited
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Process {
+    pub pid: u32,
+    pub name: String,
+}
+
+/// Makes tracer adopt the descendants of its commands whose parent exited,
+/// rather than init, so that they can still be found and reaped.
+#[cfg(target_os = "linux")]
+pub fn become_subreaper() {
+    if unsafe { libc::prctl(libc::PR_SET_CHILD_SUBREAPER, 1, 0, 0, 0) } == -1 {
+        warn!(
+            "unable to become a subreaper: {}",
+            io::Error::last_os_error()
+        );
+    }
+}
+
+#[cfg(not(target_os = "linux"))]
+pub fn become_subreaper() {}
+
+/// Has the calling process killed when its parent dies. Only meant to be
+/// called in the child between `fork` and `exec`, with the pid of the parent.
+/// Linux delivers the signal when the thread that forked exits, which is fine
+/// as commands are spawned by the future `#[tokio::main]` blocks on, i.e. from
+/// the main thread which only exits with tracer. Spawning them from a task of
+/// the runtime would kill them whenever the thread running it exits.
+#[cfg(target_os = "linux")]
+pub fn die_with_parent(parent: u32) -> io::Result<()> {
+    unsafe {
+        if libc::prctl(libc::PR_SET_PDEATHSIG, libc::SIGKILL, 0, 0, 0) == -1 {
+            return Err(io::Error::last_os_error());
+        }
+        // the parent may have died before the signal was armed
+        if libc::getppid() as u32 != parent {
+            return Err(io::Error::other("tracer exited"));
+        }
+    }
+    Ok(())
+}
+
+#[cfg(not(target_os = "linux"))]
+pub fn die_with_parent(_parent: u32) -> io::Result<()> {
+    Ok(())
+}
+
+/// Spawns a command and records it as running so that it is not mistaken
+/// for a process left behind by another one, which cannot be looked for in
+/// the meantime.
+pub fn spawn(command: &mut std::process::Command) -> io::Result<SharedChild> {
+    let mut commands = COMMANDS.lock().unwrap_or_else(|error| error.into_inner());
+    let child = SharedChild::spawn(command)?;
+    commands.get_or_insert_with(HashSet::new).insert(child.id());
+    Ok(child)
+}
+
+/// Runs a command to completion and collects its output like
+/// `Command::output`, recording it as running meanwhile so that it is not
+/// mistaken for a process left behind by a command.
+pub fn output(command: &mut std::process::Command) -> io::Result<Output> {
+    command
+        .stdin(Stdio::null())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped());
+    let child = {
+        let mut commands = COMMANDS.lock().unwrap_or_else(|error| error.into_inner());
+        let child = command.spawn()?;
+        commands.get_or_insert_with(HashSet::new).insert(child.id());
+        child
+    };
+    let pid = child.id();
+    let output = child.wait_with_output();
+    let mut commands = COMMANDS.lock().unwrap_or_else(|error| error.into_inner());
+    commands.get_or_insert_with(HashSet::new).remove(&pid);
+    output
+}
+
+/// Terminates the processes left behind by the command with the given pid,
+/// i.e. what remains of its process group or session along with their
+/// descendants, and the processes tracer adopted, and returns them. They are
+/// sent SIGTERM, then SIGKILL if they are still running after a grace period,
+/// and reaped when tracer adopted them.
+pub fn reap(pid: u32) -> Vec<Process> {
+    let lingering = {
+        let mut commands = COMMANDS.lock().unwrap_or_else(|error| error.into_inner());
+        let commands = commands.get_or_insert_with(HashSet::new);
+        commands.remove(&pid);
+        descendants(pid, commands)
+    };
+    if lingering.is_empty() {
+        return lingering;
+    }
+    signal_all(&lingering, libc::SIGTERM);
+    let deadline = Instant::now() + LINGER_GRACE_PERIOD;
+    while Instant::now() < deadline {
+        wait_all(&lingering);
+        if lingering.iter().all(|process| !is_running(process.pid)) {
+            return lingering;
+        }
+        thread::sleep(LINGER_POLL_INTERVAL);
+    }
+    signal_all(&lingering, libc::SIGKILL);
+    thread::sleep(LINGER_POLL_INTERVAL);
+    wait_all(&lingering);
+    lingering
+}
+
+fn signal_all(processes: &[Process], signal: i32) {
+    for process in processes {
+        unsafe { libc::kill(process.pid as i32, signal) };
+    }
+}
+
+/// reaps the processes that became children of tracer and exited
+fn wait_all(processes: &[Process]) {
+    for process in processes {
+        let mut status = 0;
+        unsafe { libc::waitpid(process.pid as i32, &mut status, libc::WNOHANG) };
+    }
+}
+
+#[cfg(target_os = "linux")]
+fn is_running(pid: u32) -> bool {
+    matches!(stat(pid), Some(stat) if stat.state != 'Z')
+}
+
+#[cfg(not(target_os = "linux"))]
+fn is_running(pid: u32) -> bool {
+    unsafe { libc::kill(pid as i32, 0) == 0 }
+}
+
+/// The fields of `/proc/<pid>/stat` needed to find lingering processes
+#[cfg(target_os = "linux")]
+#[derive(Debug)]
+struct Stat {
+    pid: u32,
+    name: String,
+    state: char,
+    parent: u32,
+    group: u32,
+    session: u32,
+}
+
+#[cfg(target_os = "linux")]
+fn parse_stat(line: &str) -> Option<Stat> {
+    // the name is between parentheses and may contain anything, including
+    // spaces and parentheses
+    let open = line.find('(')?;
+    let close = line.rfind(')')?;
+    let pid = line[..open].trim().parse().ok()?;
+    let name = line[open + 1..close].to_string();
+    let mut fields = line[close + 1..].split_whitespace();
+    let state = fields.next()?.chars().next()?;
+    let parent = fields.next()?.parse().ok()?;
+    let group = fields.next()?.parse().ok()?;
+    let session = fields.next()?.parse().ok()?;
+    Some(Stat {
+        pid,
+        name,
+        state,
+        parent,
+        group,
+        session,
+    })
+}
+
+#[cfg(target_os = "linux")]
+fn stat(pid: u32) -> Option<Stat> {
+    let line = std::fs::read_to_string(format!("/proc/{}/stat", pid)).ok()?;
+    parse_stat(&line)
+}
+
+#[cfg(target_os = "linux")]
+fn descendants(pid: u32, commands: &HashSet<u32>) -> Vec<Process> {
+    let tracer = std::process::id();
+    let entries = match std::fs::read_dir("/proc") {
+        Ok(entries) => entries,
+        Err(error) => {
+            warn!("unable to list processes: {}", error);
+            return vec![];
+        }
+    };
+    let (zombies, processes): (Vec<Stat>, Vec<Stat>) = entries
+        .filter_map(|entry| entry.ok()?.file_name().to_str()?.parse().ok())
+        .filter_map(stat)
+        .partition(|stat| stat.state == 'Z');
+    // the adopted processes which already exited only need to be reaped
+    for zombie in zombies {
+        if zombie.parent == tracer && zombie.pid != pid && !commands.contains(&zombie.pid) {
+            let mut status = 0;
+            unsafe { libc::waitpid(zombie.pid as i32, &mut status, libc::WNOHANG) };
+        }
+    }
+
+    let mut found: Vec<u32> = processes
+        .iter()
+        .filter(|stat| stat.pid != pid)
+        .filter(|stat| {
+            let adopted = stat.parent == tracer && !commands.contains(&stat.pid);
+            stat.group == pid || stat.session == pid || adopted
+        })
+        .map(|stat| stat.pid)
+        .collect();
+    // add the descendants that moved to a group or session of their own while
+    // their parent is still running
+    let mut index = 0;
+    while index < found.len() {
+        let parent = found[index];
+        for stat in &processes {
+            if stat.parent == parent && !found.contains(&stat.pid) {
+                found.push(stat.pid);
+            }
+        }
+        index += 1;
+    }
+
+    processes
+        .into_iter()
+        .filter(|stat| found.contains(&stat.pid))
+        .map(|stat| Process {
+            pid: stat.pid,
+            name: stat.name,
+        })
+        .collect()
+}
+
+#[cfg(not(target_os = "linux"))]
+fn descendants(_pid: u32, _commands: &HashSet<u32>) -> Vec<Process> {
+    vec![]
+}
+
+#[cfg(all(test, target_os = "linux"))]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_parse_stat() {
+        let stat = parse_stat("4242 (my (weird) cmd) S 4200 4201 4201 0 -1 4194560").unwrap();
+        assert_eq!(stat.pid, 4242);
+        assert_eq!(stat.name, "my (weird) cmd");
+        assert_eq!(stat.state, 'S');
+        assert_eq!(stat.parent, 4200);
+        assert_eq!(stat.group, 4201);
+        assert_eq!(stat.session, 4201);
+    }
+}
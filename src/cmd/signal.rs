@@ -0,0 +1,90 @@
+use std::convert::TryFrom;
+use std::fmt;
+
+use serde::{Deserialize, Serialize};
+
+/// The signals that can be configured by name
+const SIGNALS: [(&str, i32); 7] = [
+    ("SIGHUP", libc::SIGHUP),
+    ("SIGINT", libc::SIGINT),
+    ("SIGQUIT", libc::SIGQUIT),
+    ("SIGKILL", libc::SIGKILL),
+    ("SIGUSR1", libc::SIGUSR1),
+    ("SIGUSR2", libc::SIGUSR2),
+    ("SIGTERM", libc::SIGTERM),
+];
+
+/// A signal sent to a command, written by name with or without the `SIG`
+/// prefix (e.g. `SIGTERM` or `TERM`) or by number.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(try_from = "String", into = "String")]
+pub struct Signal(i32);
+
+impl Signal {
+    pub const TERM: Signal = Signal(libc::SIGTERM);
+    pub const KILL: Signal = Signal(libc::SIGKILL);
+
+    pub fn number(&self) -> i32 {
+        self.0
+    }
+}
+
+impl Default for Signal {
+    fn default() -> Self {
+        Self::TERM
+    }
+}
+
+impl fmt::Display for Signal {
+    fn fmt(&self, formatter: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match SIGNALS.iter().find(|(_, number)| *number == self.0) {
+            Some((name, _)) => write!(formatter, "{}", name),
+            None => write!(formatter, "{}", self.0),
+        }
+    }
+}
+
+impl TryFrom<String> for Signal {
+    type Error = String;
+
+    fn try_from(value: String) -> Result<Self, Self::Error> {
+        if let Ok(number) = value.parse::<i32>() {
+            if number > 0 && number < 32 {
+                return Ok(Signal(number));
+            }
+        }
+        let name = value.trim().to_uppercase();
+        let name = if name.starts_with("SIG") {
+            name
+        } else {
+            format!("SIG{}", name)
+        };
+        SIGNALS
+            .iter()
+            .find(|(known, _)| *known == name)
+            .map(|(_, number)| Signal(*number))
+            .ok_or_else(|| format!("unknown signal '{}'", value))
+    }
+}
+
+impl From<Signal> for String {
+    fn from(signal: Signal) -> Self {
+        signal.to_string()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_parse_signal() {
+        for value in ["SIGTERM", "TERM", "term", "15"] {
+            assert_eq!(Signal::try_from(value.to_string()), Ok(Signal::TERM));
+        }
+        assert_eq!(Signal::try_from("SIGKILL".to_string()), Ok(Signal::KILL));
+        assert!(Signal::try_from("SIGNOPE".to_string()).is_err());
+        assert!(Signal::try_from("0".to_string()).is_err());
+        assert_eq!(Signal::KILL.to_string(), "SIGKILL");
+    }
+}
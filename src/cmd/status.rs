@@ -0,0 +1,60 @@
+use std::fmt;
+
+/// The offset added to a signal number when a command was killed by a
+/// signal, following the convention of POSIX shells.
+const SIGNAL_EXIT_OFFSET: i32 = 128;
+
+/// How a spawned command finished.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ExitStatus {
+    code: Option<i32>,
+    signal: Option<i32>,
+}
+
+impl ExitStatus {
+    pub fn new(code: Option<i32>, signal: Option<i32>) -> Self {
+        Self { code, signal }
+    }
+
+    pub fn code(&self) -> Option<i32> {
+        self.code
+    }
+
+    pub fn signal(&self) -> Option<i32> {
+        self.signal
+    }
+
+    /// The code tracer exits with to mirror the command: its own exit code,
+    /// or 128 + the signal number when it was killed by a signal.
+    pub fn exit_code(&self) -> i32 {
+        match (self.code, self.signal) {
+            (Some(code), _) => code,
+            (None, Some(signal)) => SIGNAL_EXIT_OFFSET + signal,
+            (None, None) => 1,
+        }
+    }
+}
+
+impl fmt::Display for ExitStatus {
+    fn fmt(&self, formatter: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match (self.code, self.signal) {
+            (Some(code), _) => write!(formatter, "exit code {}", code),
+            (None, Some(signal)) => write!(formatter, "signal {}", signal),
+            (None, None) => write!(formatter, "unknown status"),
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_exit_code() {
+        assert_eq!(ExitStatus::new(Some(0), None).exit_code(), 0);
+        assert_eq!(ExitStatus::new(Some(3), None).exit_code(), 3);
+        assert_eq!(ExitStatus::new(None, Some(9)).exit_code(), 137);
+        assert_eq!(ExitStatus::new(None, Some(15)).exit_code(), 143);
+        assert_eq!(ExitStatus::new(None, None).exit_code(), 1);
+    }
+}
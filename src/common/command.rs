@@ -8,8 +8,25 @@ pub struct Command {
 #[derive(Serialize, Deserialize)]
 pub struct CommandCreate {
     name: String,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    tags: Vec<String>,
 }
 
+impl CommandCreate {
+    pub fn new(name: impl Into<String>) -> Self {
+        Self {
+            name: name.into(),
+            tags: vec![],
+        }
+    }
+
+    pub fn tags(mut self, tags: &[String]) -> Self {
+        self.tags = tags.to_vec();
+        self
+    }
+}
+
+#[allow(dead_code)]
 impl Command {
     pub fn new(id: String) -> Self {
         Self { id }
@@ -1,22 +1,132 @@
-#![allow(dead_code)]
+use std::collections::HashMap;
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+
+use crate::cmd::{CaptureMode, WindowSize};
+use crate::config::configuration::NamedCommand;
+
 pub struct ApplicationConfig {
     executable: String,
+    args: Vec<String>,
     name: String,
+    tty: Option<WindowSize>,
+    capture: CaptureMode,
+    timeout: Option<Duration>,
+    idle_timeout: Option<Duration>,
+    env: HashMap<String, String>,
+    directory: Option<PathBuf>,
+    tags: Vec<String>,
 }
 
 impl ApplicationConfig {
     pub fn new(executable: &str, name: &str) -> Self {
         ApplicationConfig {
             executable: executable.into(),
+            args: Default::default(),
             name: name.into(),
+            tty: None,
+            capture: Default::default(),
+            timeout: None,
+            idle_timeout: None,
+            env: Default::default(),
+            directory: None,
+            tags: Default::default(),
+        }
+    }
+
+    /// Appends arguments for the executable, keeping their boundaries intact.
+    pub fn args<I, S>(mut self, args: I) -> Self
+    where
+        I: IntoIterator<Item = S>,
+        S: AsRef<str>,
+    {
+        for arg in args {
+            self.args.push(arg.as_ref().to_string());
+        }
+        self
+    }
+
+    /// Runs the executable under a pseudo-terminal of the given size.
+    pub fn tty(mut self, size: WindowSize) -> Self {
+        self.tty = Some(size);
+        self
+    }
+
+    /// Sets how the output of the executable is split into packets.
+    pub fn capture(mut self, mode: CaptureMode) -> Self {
+        self.capture = mode;
+        self
+    }
+
+    /// Stops the executable when it runs for longer than the given duration.
+    pub fn timeout(mut self, timeout: Option<Duration>) -> Self {
+        self.timeout = timeout;
+        self
+    }
+
+    /// Stops the executable when it writes no output for longer than the
+    /// given duration.
+    pub fn idle_timeout(mut self, timeout: Option<Duration>) -> Self {
+        self.idle_timeout = timeout;
+        self
+    }
+
+    /// Applies the settings configured for the command name, unless they were
+    /// already set.
+    pub fn defaults(mut self, command: &NamedCommand) -> Self {
+        self.timeout = self.timeout.or_else(|| command.timeout());
+        self.idle_timeout = self.idle_timeout.or_else(|| command.idle_timeout());
+        for (name, value) in command.env() {
+            self.env
+                .entry(name.clone())
+                .or_insert_with(|| value.clone());
+        }
+        if self.directory.is_none() {
+            self.directory = command.working_directory().map(Path::to_path_buf);
         }
+        if self.tags.is_empty() {
+            self.tags = command.tags().to_vec();
+        }
+        self
     }
 
     pub fn executable(&self) -> &str {
         &self.executable
     }
 
+    pub fn arguments(&self) -> &[String] {
+        &self.args
+    }
+
     pub fn name(&self) -> &str {
         &self.name
     }
+
+    pub fn window_size(&self) -> Option<WindowSize> {
+        self.tty
+    }
+
+    pub fn capture_mode(&self) -> CaptureMode {
+        self.capture
+    }
+
+    pub fn max_duration(&self) -> Option<Duration> {
+        self.timeout
+    }
+
+    pub fn max_idle(&self) -> Option<Duration> {
+        self.idle_timeout
+    }
+
+    pub fn env(&self) -> &HashMap<String, String> {
+        &self.env
+    }
+
+    pub fn working_directory(&self) -> Option<&Path> {
+        self.directory.as_deref()
+    }
+
+    pub fn tags(&self) -> &[String] {
+        &self.tags
+    }
 }
@@ -1,6 +1,6 @@
 pub use command::{Command, CommandCreate};
-pub use session::Session;
 pub use config::ApplicationConfig;
+pub use session::Session;
 mod command;
 pub mod config;
 mod session;
@@ -6,6 +6,7 @@ pub struct Session {
     token: String,
 }
 
+#[allow(dead_code)]
 impl Session {
     pub fn new(id: String, token: String) -> Self {
         Self { id, token }
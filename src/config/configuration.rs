@@ -1,15 +1,38 @@
-use std::fs::File;
-use std::io::BufReader;
-use std::path::Path;
+use std::collections::{BTreeMap, HashMap};
+use std::path::{Path, PathBuf};
 use std::str::FromStr;
+use std::time::Duration;
 
 use serde::{Deserialize, Serialize};
+use serde_yaml::Value;
 
+use crate::cmd::{CaptureMode, Signal};
+use crate::config::layers::{lookup, Kind, Layers, Source, SETTINGS};
+use crate::config::token::{self, Secret};
+use crate::config::validate;
 use crate::error::ApplicationError;
 
 #[derive(Debug, PartialEq, Serialize, Deserialize, Clone)]
 pub struct Configuration {
     environment: Environment,
+    /// settings for the commands run under a given name
+    #[serde(default)]
+    commands: HashMap<String, NamedCommand>,
+    /// settings overriding those of the environment, by server
+    #[serde(default)]
+    profiles: BTreeMap<String, Value>,
+    /// the profile used unless another one is selected
+    #[serde(default)]
+    default_profile: Option<String>,
+    /// the profile the environment was built with
+    #[serde(skip)]
+    profile: Option<String>,
+    /// where the settings of the environment come from
+    #[serde(skip)]
+    sources: BTreeMap<String, Source>,
+    /// the file the configuration was read from, if any
+    #[serde(skip)]
+    path: Option<PathBuf>,
 }
 
 impl FromStr for Configuration {
@@ -26,31 +49,189 @@ impl Configuration {
         &self.environment
     }
 
+    /// The settings of the command run under the given name, if any
+    pub fn command(&self, name: &str) -> Option<&NamedCommand> {
+        self.commands.get(name)
+    }
+
     #[cfg(test)]
     pub fn from_str(s: &str) -> Result<Self, ApplicationError> {
         let configuration = serde_yaml::from_str::<Configuration>(s)?;
         Ok(configuration)
     }
 
-    pub fn from_path(path: &Path) -> Result<Self, ApplicationError> {
-        let file =
-            File::open(path).map_err(|_| ApplicationError::configuration("Unable to open file"))?;
-        let reader = BufReader::new(file);
-        serde_yaml::from_reader(reader).map_err(|e| e.into())
+    /// Builds the configuration of a document read from the given path whose
+    /// environment is replaced by the given layers, built with the given
+    /// profile
+    pub fn from_layers(
+        path: Option<PathBuf>,
+        document: Value,
+        layers: Layers,
+        profile: Option<String>,
+    ) -> Result<Self, ApplicationError> {
+        let mut document = match document {
+            Value::Mapping(document) => document,
+            Value::Null => Default::default(),
+            _ => {
+                return Err(ApplicationError::configuration(
+                    "the configuration is not a mapping",
+                ))
+            }
+        };
+        let (environment, sources) = layers.into_parts();
+        document.insert(Value::String("environment".into()), environment);
+        // deserialising text rather than a value tells which setting is wrong
+        let text = serde_yaml::to_string(&Value::Mapping(document))?;
+        let mut configuration: Configuration = serde_yaml::from_str(&text)
+            .map_err(|error| ApplicationError::configuration(validate::without_location(&error)))?;
+        configuration.sources = sources;
+        configuration.profile = profile;
+        configuration.path = path;
+        Ok(configuration)
+    }
+
+    /// The file the configuration was read from, if any
+    pub fn path(&self) -> Option<&Path> {
+        self.path.as_deref()
+    }
+
+    /// The profiles as (name, host), the host being that of the environment
+    /// when the profile does not set one
+    pub fn profiles(&self) -> Vec<(&str, &str)> {
+        self.profiles
+            .iter()
+            .map(|(name, profile)| {
+                let host = profile
+                    .get("host")
+                    .and_then(Value::as_str)
+                    .unwrap_or(&self.environment.host);
+                (name.as_str(), host)
+            })
+            .collect()
+    }
+
+    pub fn default_profile(&self) -> Option<&str> {
+        self.default_profile.as_deref()
+    }
+
+    /// The profile the environment was built with, if any
+    pub fn profile(&self) -> Option<&str> {
+        self.profile.as_deref()
+    }
+
+    /// The effective settings of the environment as (key, value, source),
+    /// with secrets masked
+    pub fn settings(&self) -> Vec<(&'static str, String, Source)> {
+        let environment = serde_yaml::to_value(&self.environment).unwrap_or_default();
+        SETTINGS
+            .iter()
+            .map(|setting| {
+                let value = match lookup(&environment, setting.key) {
+                    None | Some(Value::Null) => "(not set)".to_string(),
+                    Some(_) if setting.kind == Kind::Secret => "********".to_string(),
+                    Some(Value::String(value)) => value.clone(),
+                    Some(value) => serde_yaml::to_string(value)
+                        .map(|value| value.trim_start_matches("---").trim().to_string())
+                        .unwrap_or_default(),
+                };
+                let source = self
+                    .sources
+                    .get(setting.key)
+                    .cloned()
+                    .unwrap_or(Source::Default);
+                (setting.key, value, source)
+            })
+            .collect()
     }
 }
 
 #[derive(Debug, PartialEq, Serialize, Deserialize, Clone)]
 pub struct Environment {
     host: String,
+    #[serde(default)]
     https: bool,
-    token: String,
+    /// the API token, when given in the configuration itself
+    #[serde(default)]
+    token: Option<Secret>,
+    /// a file holding the API token
+    #[serde(default)]
+    token_file: Option<PathBuf>,
+    /// an environment variable holding the API token
+    #[serde(default)]
+    token_env: Option<String>,
+    /// a command printing the API token
+    #[serde(default)]
+    token_command: Option<String>,
+    /// seconds the token printed by the token command is reused for
+    #[serde(default = "default_token_ttl")]
+    token_ttl: u64,
+    #[serde(default = "default_logging")]
     logging: String,
+    /// where to write the logs, stderr when not set
+    #[serde(default)]
+    log_file: Option<PathBuf>,
+    #[serde(default)]
+    reconnect: Reconnect,
+    #[serde(default)]
+    spool: Spool,
+    /// whether the server may write to the standard input of the command
+    #[serde(default)]
+    remote_input: bool,
+    #[serde(default)]
+    terminate: Terminate,
+    #[serde(default)]
+    shutdown: Shutdown,
+    #[serde(default)]
+    heartbeat: Heartbeat,
+}
+
+fn default_logging() -> String {
+    "INFO".to_string()
+}
+
+fn default_token_ttl() -> u64 {
+    300
 }
 
 impl Environment {
-    pub fn token(&self) -> &String {
-        &self.token
+    pub fn host(&self) -> &str {
+        &self.host
+    }
+
+    /// The API token, read from whichever source is configured
+    pub fn token(&self) -> Result<Secret, ApplicationError> {
+        if let Some(token) = &self.token {
+            return Ok(token.clone());
+        }
+        if let Some(path) = &self.token_file {
+            return token::from_file(path);
+        }
+        if let Some(name) = &self.token_env {
+            return token::from_variable(name);
+        }
+        if let Some(command) = &self.token_command {
+            return token::from_command(command, Duration::from_secs(self.token_ttl));
+        }
+        Err(ApplicationError::configuration("no token is configured"))
+    }
+
+    /// What is worth fixing in the environment although it is valid
+    pub fn warnings(&self) -> Vec<String> {
+        self.token_file
+            .as_deref()
+            .and_then(token::file_warning)
+            .into_iter()
+            .collect()
+    }
+
+    /// Whether the token is read from another source than in the given
+    /// environment
+    pub fn token_changed(&self, other: &Environment) -> bool {
+        self.token != other.token
+            || self.token_file != other.token_file
+            || self.token_env != other.token_env
+            || self.token_command != other.token_command
+            || self.token_ttl != other.token_ttl
     }
 
     pub fn ws_url(&self) -> String {
@@ -70,6 +251,311 @@ impl Environment {
     pub fn logging(&self) -> &str {
         &self.logging
     }
+
+    pub fn log_file(&self) -> Option<&Path> {
+        self.log_file.as_deref()
+    }
+
+    pub fn reconnect(&self) -> &Reconnect {
+        &self.reconnect
+    }
+
+    pub fn spool(&self) -> &Spool {
+        &self.spool
+    }
+
+    pub fn remote_input(&self) -> bool {
+        self.remote_input
+    }
+
+    pub fn terminate(&self) -> &Terminate {
+        &self.terminate
+    }
+
+    pub fn shutdown(&self) -> &Shutdown {
+        &self.shutdown
+    }
+
+    pub fn heartbeat(&self) -> &Heartbeat {
+        &self.heartbeat
+    }
+
+    /// Checks the settings that deserialising does not
+    pub fn validate(&self) -> Result<(), ApplicationError> {
+        let problems = self.problems();
+        if problems.is_empty() {
+            return Ok(());
+        }
+        Err(ApplicationError::configuration(problems.join(", ")))
+    }
+
+    /// What is wrong with the settings that deserialising does not check
+    pub fn problems(&self) -> Vec<String> {
+        validate::environment(&serde_yaml::to_value(self).unwrap_or_default())
+    }
+
+    /// The settings changed in the given environment which only take effect
+    /// once tracer is restarted, the others can be applied while it runs
+    pub fn restart_required(&self, other: &Environment) -> Vec<&'static str> {
+        let mut changed = vec![];
+        if self.host != other.host {
+            changed.push("host");
+        }
+        if self.https != other.https {
+            changed.push("https");
+        }
+        if self.log_file != other.log_file {
+            changed.push("log_file");
+        }
+        if self.reconnect != other.reconnect {
+            changed.push("reconnect");
+        }
+        if self.spool != other.spool {
+            changed.push("spool");
+        }
+        if self.remote_input != other.remote_input {
+            changed.push("remote_input");
+        }
+        if self.heartbeat != other.heartbeat {
+            changed.push("heartbeat");
+        }
+        changed
+    }
+
+    /// Takes the settings which can change while tracer runs from the given
+    /// environment
+    pub fn apply(&mut self, other: &Environment) {
+        self.logging = other.logging.clone();
+        self.token = other.token.clone();
+        self.token_file = other.token_file.clone();
+        self.token_env = other.token_env.clone();
+        self.token_command = other.token_command.clone();
+        self.token_ttl = other.token_ttl;
+        self.terminate = other.terminate.clone();
+        self.shutdown = other.shutdown.clone();
+    }
+}
+
+/// How the websocket is re-established when the connection is lost
+#[derive(Debug, PartialEq, Serialize, Deserialize, Clone)]
+#[serde(default)]
+pub struct Reconnect {
+    /// seconds to wait before the first attempt
+    initial_delay: u64,
+    /// the upper bound in seconds of the delay between attempts
+    max_delay: u64,
+    /// attempts to make before giving up
+    max_attempts: u32,
+}
+
+impl Default for Reconnect {
+    fn default() -> Self {
+        Self {
+            initial_delay: 1,
+            max_delay: 30,
+            max_attempts: 20,
+        }
+    }
+}
+
+impl Reconnect {
+    pub fn initial_delay(&self) -> Duration {
+        Duration::from_secs(self.initial_delay)
+    }
+
+    pub fn max_delay(&self) -> Duration {
+        Duration::from_secs(self.max_delay)
+    }
+
+    pub fn max_attempts(&self) -> u32 {
+        self.max_attempts
+    }
+}
+
+/// What happens to new output when the spool has reached its size cap
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum FullPolicy {
+    /// discard the oldest spooled output to make room
+    DropOldest,
+    /// discard the new output
+    DropNewest,
+    /// stop reading the pipes of the command until there is room again
+    Block,
+}
+
+/// How output is buffered on disk while the websocket is down
+#[derive(Debug, PartialEq, Serialize, Deserialize, Clone)]
+#[serde(default)]
+pub struct Spool {
+    /// the maximum size in bytes of the output waiting to be sent
+    max_size: u64,
+    when_full: FullPolicy,
+}
+
+impl Default for Spool {
+    fn default() -> Self {
+        Self {
+            max_size: 64 * 1024 * 1024,
+            when_full: FullPolicy::DropOldest,
+        }
+    }
+}
+
+impl Spool {
+    pub fn max_size(&self) -> u64 {
+        self.max_size
+    }
+
+    pub fn when_full(&self) -> FullPolicy {
+        self.when_full
+    }
+}
+
+/// How the command is stopped when the server or a local signal asks for it
+#[derive(Debug, PartialEq, Serialize, Deserialize, Clone)]
+#[serde(default)]
+pub struct Terminate {
+    /// the signal sent to the process group of the command first
+    signal: Signal,
+    /// seconds to wait for the command to exit before it is killed
+    grace_period: u64,
+}
+
+impl Default for Terminate {
+    fn default() -> Self {
+        Self {
+            signal: Signal::TERM,
+            grace_period: 10,
+        }
+    }
+}
+
+impl Terminate {
+    pub fn signal(&self) -> Signal {
+        self.signal
+    }
+
+    pub fn grace_period(&self) -> Duration {
+        Duration::from_secs(self.grace_period)
+    }
+}
+
+/// How tracer shuts down on SIGINT, SIGTERM or SIGQUIT
+#[derive(Debug, PartialEq, Serialize, Deserialize, Clone)]
+#[serde(default)]
+pub struct Shutdown {
+    /// seconds to stop the command and send its remaining output within,
+    /// after which tracer exits regardless
+    drain_timeout: u64,
+}
+
+impl Default for Shutdown {
+    fn default() -> Self {
+        Self { drain_timeout: 30 }
+    }
+}
+
+impl Shutdown {
+    pub fn drain_timeout(&self) -> Duration {
+        Duration::from_secs(self.drain_timeout)
+    }
+}
+
+/// How often the server is pinged, and when the connection is deemed dead
+#[derive(Debug, PartialEq, Serialize, Deserialize, Clone)]
+#[serde(default)]
+pub struct Heartbeat {
+    /// seconds between pings
+    interval: u64,
+    /// seconds to wait for the pong answering a ping
+    pong_timeout: u64,
+    /// pongs missed in a row after which the connection is re-established
+    max_missed: u32,
+}
+
+impl Default for Heartbeat {
+    fn default() -> Self {
+        Self {
+            interval: 5,
+            pong_timeout: 5,
+            max_missed: 3,
+        }
+    }
+}
+
+impl Heartbeat {
+    pub fn interval(&self) -> Duration {
+        Duration::from_secs(self.interval)
+    }
+
+    pub fn pong_timeout(&self) -> Duration {
+        Duration::from_secs(self.pong_timeout)
+    }
+
+    pub fn max_missed(&self) -> u32 {
+        self.max_missed
+    }
+}
+
+/// The settings of a command run under a given name, and what it runs unless
+/// the command line says otherwise
+#[derive(Debug, Default, PartialEq, Serialize, Deserialize, Clone)]
+#[serde(default)]
+pub struct NamedCommand {
+    executable: Option<String>,
+    /// extended by the arguments given on the command line
+    args: Vec<String>,
+    /// variables added to the environment of the command
+    env: HashMap<String, String>,
+    working_directory: Option<PathBuf>,
+    /// seconds the command may run for before it is stopped
+    timeout: Option<u64>,
+    /// seconds the command may go without writing output before it is stopped
+    idle_timeout: Option<u64>,
+    /// whether the command runs under a pseudo-terminal
+    tty: bool,
+    capture: Option<CaptureMode>,
+    /// labels sent to the server along with the name of the command
+    tags: Vec<String>,
+}
+
+impl NamedCommand {
+    pub fn executable(&self) -> Option<&str> {
+        self.executable.as_deref()
+    }
+
+    pub fn args(&self) -> &[String] {
+        &self.args
+    }
+
+    pub fn env(&self) -> &HashMap<String, String> {
+        &self.env
+    }
+
+    pub fn working_directory(&self) -> Option<&Path> {
+        self.working_directory.as_deref()
+    }
+
+    pub fn tty(&self) -> bool {
+        self.tty
+    }
+
+    pub fn capture(&self) -> Option<CaptureMode> {
+        self.capture
+    }
+
+    pub fn tags(&self) -> &[String] {
+        &self.tags
+    }
+
+    pub fn timeout(&self) -> Option<Duration> {
+        self.timeout.map(Duration::from_secs)
+    }
+
+    pub fn idle_timeout(&self) -> Option<Duration> {
+        self.idle_timeout.map(Duration::from_secs)
+    }
 }
 
 #[cfg(test)]
@@ -90,13 +576,235 @@ mod test {
             environment: Environment {
                 host: "localhost:8080".to_string(),
                 https: false,
-                token: "a super long token".to_string(),
+                token: Some(Secret::new("a super long token")),
+                token_file: None,
+                token_env: None,
+                token_command: None,
+                token_ttl: 300,
                 logging: "DEBUG".to_string(),
+                log_file: None,
+                reconnect: Reconnect::default(),
+                spool: Spool::default(),
+                remote_input: false,
+                terminate: Terminate::default(),
+                shutdown: Shutdown::default(),
+                heartbeat: Heartbeat::default(),
             },
+            commands: HashMap::new(),
+            profiles: BTreeMap::new(),
+            default_profile: None,
+            profile: None,
+            sources: BTreeMap::new(),
+            path: None,
         };
 
         let deserialised_configuration: Configuration = Configuration::from_str(yaml).unwrap();
 
         assert_eq!(configuration, deserialised_configuration);
     }
+
+    /// The environment the decoding tests share, with the given section
+    fn environment(section: &str) -> Environment {
+        let yaml = format!(
+            "host: localhost:8080\nhttps: false\ntoken: a super long token\nlogging: DEBUG\n{}",
+            section
+        );
+        serde_yaml::from_str(&yaml).unwrap()
+    }
+
+    #[test]
+    fn test_decode_reconnect() {
+        let environment = environment("reconnect: {max_delay: 60}");
+        let reconnect = environment.reconnect();
+
+        assert_eq!(reconnect.initial_delay(), Duration::from_secs(1));
+        assert_eq!(reconnect.max_delay(), Duration::from_secs(60));
+        assert_eq!(reconnect.max_attempts(), 20);
+    }
+
+    #[test]
+    fn test_decode_spool() {
+        let environment = environment("spool: {max_size: 1024, when_full: block}");
+        let spool = environment.spool();
+
+        assert_eq!(spool.max_size(), 1024);
+        assert_eq!(spool.when_full(), FullPolicy::Block);
+    }
+
+    #[test]
+    fn test_decode_terminate() {
+        let environment = environment("terminate: {signal: INT, grace_period: 3}");
+        let terminate = environment.terminate();
+
+        assert_eq!(terminate.signal().number(), libc::SIGINT);
+        assert_eq!(terminate.grace_period(), Duration::from_secs(3));
+    }
+
+    #[test]
+    fn test_decode_shutdown() {
+        let environment = environment("shutdown: {drain_timeout: 5}");
+
+        assert_eq!(
+            environment.shutdown().drain_timeout(),
+            Duration::from_secs(5)
+        );
+    }
+
+    #[test]
+    fn test_decode_heartbeat() {
+        let environment = environment("heartbeat: {interval: 30, max_missed: 2}");
+        let heartbeat = environment.heartbeat();
+
+        assert_eq!(heartbeat.interval(), Duration::from_secs(30));
+        assert_eq!(heartbeat.pong_timeout(), Duration::from_secs(5));
+        assert_eq!(heartbeat.max_missed(), 2);
+    }
+
+    #[test]
+    fn test_restart_required() {
+        let yaml = r#"
+            environment:
+                host: localhost:8080
+                https: false
+                token: a super long token
+                logging: DEBUG
+        "#;
+        let reloaded = r#"
+            environment:
+                host: localhost:8080
+                https: false
+                token: another token
+                logging: INFO
+                remote_input: true
+                spool:
+                    max_size: 1024
+                terminate:
+                    grace_period: 3
+        "#;
+
+        let mut environment = Configuration::from_str(yaml).unwrap().environment;
+        let reloaded = Configuration::from_str(reloaded).unwrap().environment;
+
+        assert!(environment.restart_required(&environment).is_empty());
+        assert_eq!(
+            environment.restart_required(&reloaded),
+            ["spool", "remote_input"]
+        );
+
+        environment.apply(&reloaded);
+
+        assert_eq!(environment.logging(), "INFO");
+        assert_eq!(environment.token().unwrap().expose(), "another token");
+        assert_eq!(
+            environment.terminate().grace_period(),
+            Duration::from_secs(3)
+        );
+        assert_eq!(environment.spool().max_size(), 64 * 1024 * 1024);
+    }
+
+    #[test]
+    fn test_validate() {
+        let yaml = r#"
+            environment:
+                host: localhost:8080
+                https: false
+                token: a super long token
+                logging: LOUD
+        "#;
+
+        let configuration = Configuration::from_str(yaml).unwrap();
+
+        assert!(configuration.environment().validate().is_err());
+    }
+
+    #[test]
+    fn test_token_sources() {
+        let yaml = r#"
+            environment:
+                host: localhost:8080
+                token_env: TRACER_TEST_TOKEN_SOURCES
+        "#;
+        let both = r#"
+            environment:
+                host: localhost:8080
+                token: a super long token
+                token_command: pass show tracer
+        "#;
+        let none = r#"
+            environment:
+                host: localhost:8080
+        "#;
+        std::env::set_var("TRACER_TEST_TOKEN_SOURCES", " a super long token\n");
+
+        let environment = Configuration::from_str(yaml).unwrap().environment;
+        let both = Configuration::from_str(both).unwrap().environment;
+        let none = Configuration::from_str(none).unwrap().environment;
+
+        assert!(environment.validate().is_ok());
+        assert_eq!(environment.token().unwrap().expose(), "a super long token");
+        assert!(!format!("{:?}", both).contains("a super long token"));
+        assert!(both.validate().is_err());
+        assert!(none.validate().is_err());
+        assert!(none.token().is_err());
+        assert!(environment.token_changed(&both));
+    }
+
+    #[test]
+    fn test_decode_commands() {
+        let yaml = r#"
+            environment:
+                host: localhost:8080
+                https: false
+                token: a super long token
+                logging: DEBUG
+            commands:
+                nightly-backup:
+                    timeout: 3600
+                    idle_timeout: 300
+                build:
+                    idle_timeout: 60
+        "#;
+
+        let configuration = Configuration::from_str(yaml).unwrap();
+        let backup = configuration.command("nightly-backup").unwrap();
+        let build = configuration.command("build").unwrap();
+
+        assert_eq!(backup.timeout(), Some(Duration::from_secs(3600)));
+        assert_eq!(backup.idle_timeout(), Some(Duration::from_secs(300)));
+        assert_eq!(build.timeout(), None);
+        assert_eq!(build.idle_timeout(), Some(Duration::from_secs(60)));
+        assert!(configuration.command("deploy").is_none());
+    }
+
+    #[test]
+    fn test_decode_command_definition() {
+        let yaml = r#"
+            environment:
+                host: localhost:8080
+                https: false
+                token: a super long token
+                logging: DEBUG
+            commands:
+                nightly-backup:
+                    executable: restic
+                    args: [backup, /srv]
+                    env:
+                        RESTIC_REPOSITORY: /mnt/backup
+                    working_directory: /srv
+                    tty: true
+                    capture: chunks
+                    tags: [nightly, backup]
+        "#;
+
+        let configuration = Configuration::from_str(yaml).unwrap();
+        let backup = configuration.command("nightly-backup").unwrap();
+
+        assert_eq!(backup.executable(), Some("restic"));
+        assert_eq!(backup.args(), ["backup", "/srv"]);
+        assert_eq!(backup.env()["RESTIC_REPOSITORY"], "/mnt/backup");
+        assert_eq!(backup.working_directory(), Some(Path::new("/srv")));
+        assert!(backup.tty());
+        assert_eq!(backup.capture(), Some(CaptureMode::Chunks));
+        assert_eq!(backup.tags(), ["nightly", "backup"]);
+    }
 }
@@ -0,0 +1,260 @@
+use std::collections::HashMap;
+use std::fs;
+use std::path::{Path, PathBuf};
+
+use serde_yaml::Value;
+
+use crate::config::validate;
+use crate::error::ApplicationError;
+
+/// The key listing the files a configuration file includes
+const INCLUDE: &str = "include";
+
+/// Reads a configuration file without interpreting it, expanding the
+/// `${VAR}` and `${VAR:-default}` variables of its values and merging the
+/// files it includes under it
+pub fn read(path: &Path, variables: &HashMap<String, String>) -> Result<Value, ApplicationError> {
+    load(path, variables, &mut vec![])
+}
+
+/// Reads a file included by the files of the chain
+fn load(
+    path: &Path,
+    variables: &HashMap<String, String>,
+    chain: &mut Vec<PathBuf>,
+) -> Result<Value, ApplicationError> {
+    let error = |error: std::io::Error| {
+        ApplicationError::configuration(format!(
+            "unable to open the configuration file {}: {}",
+            path.display(),
+            error
+        ))
+    };
+    let canonical = fs::canonicalize(path).map_err(error)?;
+    if let Some(start) = chain.iter().position(|included| included == &canonical) {
+        let cycle: Vec<String> = chain[start..]
+            .iter()
+            .chain([&canonical])
+            .map(|path| path.display().to_string())
+            .collect();
+        return Err(ApplicationError::configuration(format!(
+            "the configuration files include each other: {}",
+            cycle.join(" -> ")
+        )));
+    }
+    let text = fs::read_to_string(path).map_err(error)?;
+    let mut document: Value = serde_yaml::from_str(&text)
+        .map_err(|error| ApplicationError::configuration(validate::yaml_error(path, &error)))?;
+    expand(&mut document, variables).map_err(|error| {
+        ApplicationError::configuration(format!("{}: {}", path.display(), error))
+    })?;
+    let includes = match &mut document {
+        Value::Mapping(document) => document.remove(&Value::from(INCLUDE)),
+        _ => None,
+    };
+    let includes = match includes {
+        None | Some(Value::Null) => vec![],
+        Some(Value::String(include)) => vec![include],
+        Some(Value::Sequence(includes)) => includes
+            .into_iter()
+            .map(|include| match include {
+                Value::String(include) => Ok(include),
+                _ => Err(()),
+            })
+            .collect::<Result<_, _>>()
+            .map_err(|_| included_paths(path))?,
+        Some(_) => return Err(included_paths(path)),
+    };
+
+    // the including file overrides the files it includes, in order
+    let mut merged = Value::Null;
+    chain.push(canonical);
+    for include in includes {
+        let include = path.parent().unwrap_or_else(|| Path::new("")).join(include);
+        let included = load(&include, variables, chain).map_err(|error| {
+            ApplicationError::configuration(format!(
+                "{} (included from {})",
+                error.message(),
+                path.display()
+            ))
+        })?;
+        merge(&mut merged, included);
+    }
+    chain.pop();
+    merge(&mut merged, document);
+    Ok(merged)
+}
+
+fn included_paths(path: &Path) -> ApplicationError {
+    ApplicationError::configuration(format!(
+        "{}: {} expects a path or a list of paths",
+        path.display(),
+        INCLUDE
+    ))
+}
+
+/// Merges a mapping into another one key by key, other values replace the
+/// existing ones
+fn merge(value: &mut Value, other: Value) {
+    match (value, other) {
+        (Value::Mapping(mapping), Value::Mapping(other)) => {
+            for (key, other) in other {
+                match mapping.get_mut(&key) {
+                    Some(value) => merge(value, other),
+                    None => {
+                        mapping.insert(key, other);
+                    }
+                }
+            }
+        }
+        (_, Value::Null) => {}
+        (value, other) => *value = other,
+    }
+}
+
+/// Expands the variables of the strings of a YAML tree. A string expanded to
+/// a number or to true or false is read as such.
+fn expand(value: &mut Value, variables: &HashMap<String, String>) -> Result<(), String> {
+    match value {
+        Value::String(text) => {
+            if let Some(expanded) = expand_text(text, variables)? {
+                *value = match serde_yaml::from_str(&expanded) {
+                    Ok(scalar @ (Value::Bool(_) | Value::Number(_))) => scalar,
+                    _ => Value::String(expanded),
+                };
+            }
+        }
+        Value::Sequence(values) => {
+            for value in values {
+                expand(value, variables)?;
+            }
+        }
+        Value::Mapping(mapping) => {
+            for (_, value) in mapping.iter_mut() {
+                expand(value, variables)?;
+            }
+        }
+        _ => {}
+    }
+    Ok(())
+}
+
+/// The text with its variables expanded, `None` when it has none. `$${` is
+/// kept as a literal `${`.
+fn expand_text(text: &str, variables: &HashMap<String, String>) -> Result<Option<String>, String> {
+    if !text.contains("${") {
+        return Ok(None);
+    }
+    let mut expanded = String::new();
+    let mut rest = text;
+    while let Some(start) = rest.find("${") {
+        if rest[..start].ends_with('$') {
+            expanded.push_str(&rest[..start - 1]);
+            expanded.push_str("${");
+            rest = &rest[start + 2..];
+            continue;
+        }
+        expanded.push_str(&rest[..start]);
+        let end = rest[start..]
+            .find('}')
+            .map(|end| start + end)
+            .ok_or_else(|| format!("unterminated variable in {}", text))?;
+        let expression = &rest[start + 2..end];
+        let (name, default) = match expression.split_once(":-") {
+            Some((name, default)) => (name, Some(default)),
+            None => (expression, None),
+        };
+        if name.is_empty() {
+            return Err(format!("a variable has no name in {}", text));
+        }
+        // like a shell, the default replaces an empty value too
+        let value = variables
+            .get(name)
+            .filter(|value| !value.is_empty() || default.is_none());
+        match (value, default) {
+            (Some(value), _) => expanded.push_str(value),
+            (None, Some(default)) => expanded.push_str(default),
+            (None, None) => {
+                return Err(format!(
+                    "the environment variable {} is not set, give a default with ${{{}:-default}}",
+                    name, name
+                ))
+            }
+        }
+        rest = &rest[end + 1..];
+    }
+    expanded.push_str(rest);
+    Ok(Some(expanded))
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn variables() -> HashMap<String, String> {
+        [
+            ("HOST", "tracer.example.com"),
+            ("EMPTY", ""),
+            ("DELAY", "60"),
+        ]
+        .iter()
+        .map(|(name, value)| (name.to_string(), value.to_string()))
+        .collect()
+    }
+
+    #[test]
+    fn test_expand() {
+        let mut document: Value = serde_yaml::from_str(
+            r#"
+                host: ${HOST}:8080
+                logging: ${LOGGING:-INFO}
+                token: ${EMPTY:-none}
+                reconnect:
+                    max_delay: ${DELAY}
+                tags: ["$${HOST}"]
+            "#,
+        )
+        .unwrap();
+
+        expand(&mut document, &variables()).unwrap();
+
+        assert_eq!(document["host"], "tracer.example.com:8080");
+        assert_eq!(document["logging"], "INFO");
+        assert_eq!(document["token"], "none");
+        assert_eq!(document["reconnect"]["max_delay"], 60);
+        assert_eq!(document["tags"][0], "${HOST}");
+        assert!(expand_text("${UNSET}", &variables()).is_err());
+        assert!(expand_text("${HOST", &variables()).is_err());
+    }
+
+    #[test]
+    fn test_include() {
+        let directory = std::env::temp_dir().join(format!("tracer-include-{}", std::process::id()));
+        fs::create_dir_all(&directory).unwrap();
+        fs::write(
+            directory.join("team.yml"),
+            "environment:\n  host: ${HOST}\n  token: team token\n  reconnect:\n    max_delay: 60\n",
+        )
+        .unwrap();
+        fs::write(
+            directory.join("tracer.yml"),
+            "include: team.yml\nenvironment:\n  reconnect:\n    max_attempts: 3\n",
+        )
+        .unwrap();
+        fs::write(directory.join("a.yml"), "include: [b.yml]\n").unwrap();
+        fs::write(directory.join("b.yml"), "include: a.yml\n").unwrap();
+
+        let document = read(&directory.join("tracer.yml"), &variables());
+        let cycle = read(&directory.join("a.yml"), &variables());
+        fs::remove_dir_all(&directory).unwrap();
+
+        let document = document.unwrap();
+        assert_eq!(document["environment"]["host"], "tracer.example.com");
+        assert_eq!(document["environment"]["reconnect"]["max_delay"], 60);
+        assert_eq!(document["environment"]["reconnect"]["max_attempts"], 3);
+        assert!(document.get(INCLUDE).is_none());
+        let cycle = cycle.unwrap_err();
+        assert!(cycle.message().contains("include each other"));
+        assert!(cycle.message().contains("a.yml -> "));
+    }
+}
@@ -0,0 +1,357 @@
+use std::collections::BTreeMap;
+use std::fmt;
+use std::path::PathBuf;
+
+use serde_yaml::{Mapping, Value};
+
+use crate::error::ApplicationError;
+
+/// The prefix of the environment variables overriding the configuration
+const VARIABLE_PREFIX: &str = "TRACER_";
+
+/// The settings the token is read from, only one of which can be set
+pub const TOKEN_SOURCES: &[&str] = &["token", "token_file", "token_env", "token_command"];
+
+/// How the raw value of a setting given as a string is interpreted
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum Kind {
+    Text,
+    /// text which is masked when shown
+    Secret,
+    Flag,
+    Number,
+}
+
+/// A setting of the environment which can be overridden
+pub struct Setting {
+    /// the dotted path of the setting, e.g. `reconnect.max_delay`
+    pub key: &'static str,
+    pub kind: Kind,
+}
+
+const fn setting(key: &'static str, kind: Kind) -> Setting {
+    Setting { key, kind }
+}
+
+/// Every setting of the environment, in the order they are shown
+pub const SETTINGS: &[Setting] = &[
+    setting("host", Kind::Text),
+    setting("https", Kind::Flag),
+    setting("token", Kind::Secret),
+    setting("token_file", Kind::Text),
+    setting("token_env", Kind::Text),
+    setting("token_command", Kind::Text),
+    setting("token_ttl", Kind::Number),
+    setting("logging", Kind::Text),
+    setting("log_file", Kind::Text),
+    setting("reconnect.initial_delay", Kind::Number),
+    setting("reconnect.max_delay", Kind::Number),
+    setting("reconnect.max_attempts", Kind::Number),
+    setting("spool.max_size", Kind::Number),
+    setting("spool.when_full", Kind::Text),
+    setting("remote_input", Kind::Flag),
+    setting("terminate.signal", Kind::Text),
+    setting("terminate.grace_period", Kind::Number),
+    setting("shutdown.drain_timeout", Kind::Number),
+    setting("heartbeat.interval", Kind::Number),
+    setting("heartbeat.pong_timeout", Kind::Number),
+    setting("heartbeat.max_missed", Kind::Number),
+];
+
+impl Setting {
+    /// The setting with the given dotted path
+    pub fn find(key: &str) -> Option<&'static Setting> {
+        SETTINGS.iter().find(|setting| setting.key == key)
+    }
+
+    /// The environment variable overriding the setting, e.g.
+    /// `TRACER_RECONNECT_MAX_DELAY`
+    pub fn variable(&self) -> String {
+        format!(
+            "{}{}",
+            VARIABLE_PREFIX,
+            self.key.replace('.', "_").to_uppercase()
+        )
+    }
+
+    /// Converts a value given as a string to the type of the setting
+    fn parse(&self, raw: &str) -> Result<Value, String> {
+        match self.kind {
+            Kind::Text | Kind::Secret => Ok(Value::String(raw.to_string())),
+            Kind::Flag => raw
+                .parse::<bool>()
+                .map(Value::Bool)
+                .map_err(|_| "expected true or false".to_string()),
+            Kind::Number => raw
+                .parse::<u64>()
+                .map(Value::from)
+                .map_err(|_| "expected a positive number".to_string()),
+        }
+    }
+}
+
+/// Where the value of a setting comes from
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Source {
+    Default,
+    File(PathBuf),
+    /// a profile of a configuration file
+    Profile(PathBuf, String),
+    Variable(String),
+    Argument(String),
+}
+
+impl Source {
+    /// The rank of the layer the source belongs to, layers overriding those
+    /// of a lower rank
+    fn rank(&self) -> u8 {
+        match self {
+            Source::Default => 0,
+            Source::File(_) => 1,
+            Source::Profile(..) => 2,
+            Source::Variable(_) => 3,
+            Source::Argument(_) => 4,
+        }
+    }
+}
+
+impl fmt::Display for Source {
+    fn fmt(&self, formatter: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Source::Default => write!(formatter, "default"),
+            Source::File(path) => write!(formatter, "file {}", path.display()),
+            Source::Profile(path, name) => {
+                write!(formatter, "file {} (profile {})", path.display(), name)
+            }
+            Source::Variable(name) => write!(formatter, "env {}", name),
+            Source::Argument(flag) => write!(formatter, "argument {}", flag),
+        }
+    }
+}
+
+/// The settings of the environment merged from the configuration file, the
+/// environment variables and the command line, each overriding the previous
+/// ones. Settings set by none of them keep the default of the environment.
+#[derive(Debug, Default)]
+pub struct Layers {
+    values: Mapping,
+    sources: BTreeMap<String, Source>,
+}
+
+impl Layers {
+    pub fn new() -> Self {
+        Default::default()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.sources.is_empty()
+    }
+
+    /// Takes the settings of the `environment`, or of a profile, of a
+    /// configuration file
+    pub fn file(&mut self, environment: &Value, source: Source) {
+        let mut leaves = vec![];
+        collect(environment, String::new(), &mut leaves);
+        for (key, value) in leaves {
+            self.put(&key, value, source.clone());
+        }
+    }
+
+    /// Takes the settings from the `TRACER_*` variables among the given ones,
+    /// failing with every variable which is invalid
+    pub fn variables<I>(&mut self, variables: I) -> Result<(), Vec<ApplicationError>>
+    where
+        I: IntoIterator<Item = (String, String)>,
+    {
+        let variables: BTreeMap<String, String> = variables
+            .into_iter()
+            .filter(|(name, _)| name.starts_with(VARIABLE_PREFIX))
+            .collect();
+        let mut errors = vec![];
+        for setting in SETTINGS {
+            let name = setting.variable();
+            if let Some(raw) = variables.get(&name) {
+                if let Err(error) = self.set(setting.key, raw, Source::Variable(name)) {
+                    errors.push(error);
+                }
+            }
+        }
+        if !errors.is_empty() {
+            return Err(errors);
+        }
+        Ok(())
+    }
+
+    /// Overrides a setting with a value given as a string
+    pub fn set(&mut self, key: &str, raw: &str, source: Source) -> Result<(), ApplicationError> {
+        let setting = Setting::find(key).ok_or_else(|| {
+            ApplicationError::configuration(format!("unknown setting {} ({})", key, source))
+        })?;
+        let value = setting.parse(raw).map_err(|error| {
+            ApplicationError::configuration(format!("invalid {} ({}): {}", key, source, error))
+        })?;
+        self.put(key, value, source);
+        Ok(())
+    }
+
+    /// Sets a setting, a token source replacing those set by lower layers so
+    /// that, say, a variable giving the token overrides the token file
+    fn put(&mut self, key: &str, value: Value, source: Source) {
+        if TOKEN_SOURCES.contains(&key) && !value.is_null() {
+            for other in TOKEN_SOURCES.iter().filter(|other| **other != key) {
+                if matches!(self.sources.get(*other), Some(set) if set.rank() < source.rank()) {
+                    self.values.remove(&Value::from(*other));
+                    self.sources.remove(*other);
+                }
+            }
+        }
+        insert(&mut self.values, key, value);
+        self.sources.insert(key.to_string(), source);
+    }
+
+    /// The merged settings
+    pub fn values(&self) -> Value {
+        Value::Mapping(self.values.clone())
+    }
+
+    /// The merged settings, and where each of them comes from
+    pub fn into_parts(self) -> (Value, BTreeMap<String, Source>) {
+        (Value::Mapping(self.values), self.sources)
+    }
+}
+
+/// Collects the values of a YAML tree which are not mappings, by dotted path
+pub fn collect(value: &Value, path: String, leaves: &mut Vec<(String, Value)>) {
+    match value {
+        Value::Mapping(mapping) => {
+            for (key, value) in mapping {
+                let key = match key.as_str() {
+                    Some(key) => key,
+                    None => continue,
+                };
+                let path = if path.is_empty() {
+                    key.to_string()
+                } else {
+                    format!("{}.{}", path, key)
+                };
+                collect(value, path, leaves);
+            }
+        }
+        _ if !path.is_empty() => leaves.push((path, value.clone())),
+        _ => {}
+    }
+}
+
+/// Sets the value at a dotted path, creating the mappings along the way
+fn insert(mapping: &mut Mapping, path: &str, value: Value) {
+    match path.split_once('.') {
+        Some((key, rest)) => {
+            let key = Value::String(key.to_string());
+            if !matches!(mapping.get(&key), Some(Value::Mapping(_))) {
+                mapping.insert(key.clone(), Value::Mapping(Mapping::new()));
+            }
+            if let Some(Value::Mapping(inner)) = mapping.get_mut(&key) {
+                insert(inner, rest, value);
+            }
+        }
+        None => {
+            mapping.insert(Value::String(path.to_string()), value);
+        }
+    }
+}
+
+/// The value at a dotted path of a YAML tree
+pub fn lookup<'a>(value: &'a Value, path: &str) -> Option<&'a Value> {
+    path.split('.').try_fold(value, |value, key| value.get(key))
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_layers_override_in_order() {
+        let file: Value = serde_yaml::from_str(
+            r#"
+                host: localhost:8080
+                token: from the file
+                reconnect:
+                    max_delay: 60
+            "#,
+        )
+        .unwrap();
+        let variables = [
+            ("TRACER_TOKEN", "from the environment"),
+            ("TRACER_RECONNECT_MAX_ATTEMPTS", "3"),
+            ("TRACER_HTTPS", "true"),
+            ("HOME", "/root"),
+        ]
+        .map(|(name, value)| (name.to_string(), value.to_string()));
+
+        let mut layers = Layers::new();
+        layers.file(&file, Source::File("tracer.yml".into()));
+        layers.variables(variables).unwrap();
+        layers
+            .set("host", "example.com", Source::Argument("--set".into()))
+            .unwrap();
+        let (values, sources) = layers.into_parts();
+
+        assert_eq!(lookup(&values, "host").unwrap(), "example.com");
+        assert_eq!(lookup(&values, "token").unwrap(), "from the environment");
+        assert_eq!(lookup(&values, "https").unwrap(), &Value::Bool(true));
+        assert_eq!(lookup(&values, "reconnect.max_delay").unwrap(), 60);
+        assert_eq!(lookup(&values, "reconnect.max_attempts").unwrap(), 3);
+        assert_eq!(sources["host"], Source::Argument("--set".into()));
+        assert_eq!(sources["token"], Source::Variable("TRACER_TOKEN".into()));
+        assert_eq!(
+            sources["reconnect.max_delay"],
+            Source::File("tracer.yml".into())
+        );
+        assert!(!sources.contains_key("logging"));
+    }
+
+    #[test]
+    fn test_token_source_replaced_by_higher_layers() {
+        let file: Value = serde_yaml::from_str("token_file: /etc/tracer/token").unwrap();
+        let profile: Value = serde_yaml::from_str("token_command: pass show tracer").unwrap();
+        let both: Value = serde_yaml::from_str("token: a token\ntoken_env: TOKEN").unwrap();
+        let variables = [("TRACER_TOKEN".to_string(), "abc".to_string())];
+
+        let mut layers = Layers::new();
+        layers.file(&file, Source::File("tracer.yml".into()));
+        layers.file(
+            &profile,
+            Source::Profile("tracer.yml".into(), "prod".into()),
+        );
+        assert!(lookup(&layers.values(), "token_file").is_none());
+        assert!(lookup(&layers.values(), "token_command").is_some());
+        layers.variables(variables).unwrap();
+        let (values, sources) = layers.into_parts();
+        assert_eq!(lookup(&values, "token").unwrap(), "abc");
+        assert!(lookup(&values, "token_command").is_none());
+        assert!(!sources.contains_key("token_command"));
+
+        // sources set by the same layer are left for validation to report
+        let mut layers = Layers::new();
+        layers.file(&both, Source::File("tracer.yml".into()));
+        assert!(lookup(&layers.values(), "token").is_some());
+        assert!(lookup(&layers.values(), "token_env").is_some());
+    }
+
+    #[test]
+    fn test_invalid_override() {
+        let mut layers = Layers::new();
+        let variables = [
+            ("TRACER_HTTPS".to_string(), "maybe".to_string()),
+            ("TRACER_TOKEN_TTL".to_string(), "soon".to_string()),
+            ("TRACER_LOGGING".to_string(), "DEBUG".to_string()),
+        ];
+
+        let errors = layers.variables(variables).unwrap_err();
+        assert_eq!(errors.len(), 2);
+        assert!(errors[0].message().starts_with("invalid https"));
+        assert!(errors[1].message().starts_with("invalid token_ttl"));
+        assert_eq!(lookup(&layers.values(), "logging").unwrap(), "DEBUG");
+        assert!(layers.set("colour", "blue", Source::Default).is_err());
+    }
+}
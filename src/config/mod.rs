@@ -1,5 +1,10 @@
 pub use configuration::Configuration;
+pub use layers::Source;
 pub use parser::ConfigurationParser;
 
 pub mod configuration;
+mod document;
+mod layers;
 mod parser;
+mod token;
+mod validate;
@@ -1,82 +1,370 @@
 use crate::config::Configuration;
+use std::collections::HashSet;
 use std::env;
-use std::path::PathBuf;
+use std::path::{Path, PathBuf};
 
+use serde_yaml::Value;
+
+use crate::config::document;
+use crate::config::layers::{Layers, Source};
+use crate::config::validate;
 use crate::error::ApplicationError;
 
+/// The environment variable selecting a profile
+const PROFILE_VARIABLE: &str = "TRACER_PROFILE";
+
+/// Reads the configuration file, and overrides its environment with the
+/// `TRACER_*` environment variables and then with the command line
 #[derive(Default)]
-pub struct ConfigurationParser;
+pub struct ConfigurationParser {
+    path: Option<PathBuf>,
+    /// the profile to use rather than the default one
+    profile: Option<String>,
+    /// the environment variables, those of the process when not set
+    variables: Option<Vec<(String, String)>>,
+    overrides: Vec<(String, String, Source)>,
+}
 
 impl ConfigurationParser {
     pub fn new() -> Self {
         Default::default()
     }
 
+    /// Use the given configuration file instead of searching the default locations
+    pub fn with_path(path: impl Into<PathBuf>) -> Self {
+        Self {
+            path: Some(path.into()),
+            ..Default::default()
+        }
+    }
+
+    /// Use the given environment variables instead of those of the process
+    #[cfg(test)]
+    pub fn variables<I>(mut self, variables: I) -> Self
+    where
+        I: IntoIterator<Item = (String, String)>,
+    {
+        self.variables = Some(variables.into_iter().collect());
+        self
+    }
+
+    /// Use the given profile of the configuration file
+    pub fn profile(mut self, profile: impl Into<String>) -> Self {
+        self.profile = Some(profile.into());
+        self
+    }
+
+    /// Overrides a setting of the environment, e.g. `reconnect.max_delay`
+    pub fn set(mut self, key: impl Into<String>, value: impl Into<String>, source: Source) -> Self {
+        self.overrides.push((key.into(), value.into(), source));
+        self
+    }
+
+    /// Reads the configuration, reporting everything wrong with it at once
     pub fn parse(&self) -> Result<Configuration, ApplicationError> {
-        return match self.find_configuration_file() {
-            Some(file) => {
-                let path = file.as_path();
-                let configuration = Configuration::from_path(path)?;
-                Ok(configuration)
+        let path = match &self.path {
+            Some(path) if !path.exists() => {
+                return Err(ApplicationError::configuration(format!(
+                    "Could not find the configuration file {}",
+                    path.display()
+                )));
             }
-            None => Err(ApplicationError::configuration(
-                "Could not find a configuration file",
-            )),
+            Some(path) => Some(path.to_owned()),
+            None => self.find_configuration_file(),
+        };
+        let variables = match &self.variables {
+            Some(variables) => variables.clone(),
+            None => env::vars().collect(),
+        };
+        let document = match &path {
+            Some(path) => document::read(path, &variables.iter().cloned().collect())?,
+            None => Value::Null,
         };
+        let mut problems = validate::keys(&document);
+        let profile = self
+            .profile
+            .clone()
+            .or_else(|| {
+                variables
+                    .iter()
+                    .find(|(name, _)| name == PROFILE_VARIABLE)
+                    .map(|(_, value)| value.clone())
+            })
+            .or_else(|| {
+                document
+                    .get("default_profile")
+                    .and_then(Value::as_str)
+                    .map(str::to_string)
+            });
+        let mut layers = Layers::new();
+        if let Some(path) = &path {
+            // the settings of a profile override those of the environment
+            if let Some(environment) = document.get("environment") {
+                layers.file(environment, Source::File(path.to_owned()));
+            }
+            if let Some(name) = &profile {
+                match Self::select(&document, name, path) {
+                    Ok(selected) => {
+                        layers.file(selected, Source::Profile(path.to_owned(), name.clone()))
+                    }
+                    Err(error) => problems.push(error.message().to_string()),
+                }
+            }
+        }
+        if let Err(errors) = layers.variables(variables) {
+            problems.extend(errors.iter().map(|error| error.message().to_string()));
+        }
+        for (key, value, source) in &self.overrides {
+            if let Err(error) = layers.set(key, value, source.clone()) {
+                problems.push(error.message().to_string());
+            }
+        }
+        if path.is_none() && layers.is_empty() {
+            let searched: Vec<String> = self
+                .candidates()
+                .iter()
+                .map(|candidate| format!("\n  {}", candidate.display()))
+                .collect();
+            return Err(ApplicationError::configuration(format!(
+                "Could not find a configuration file, searched:{}",
+                searched.concat()
+            )));
+        }
+        problems.extend(validate::environment(&layers.values()));
+        match Configuration::from_layers(path.clone(), document, layers, profile) {
+            Ok(configuration) if problems.is_empty() => return Ok(configuration),
+            Ok(_) => {}
+            Err(error) => {
+                // a setting of the wrong type is already reported with a hint
+                let key = error.message().split(": ").next().unwrap_or_default();
+                let reported = format!("invalid {},", key);
+                if !problems
+                    .iter()
+                    .any(|problem| problem.starts_with(&reported))
+                {
+                    problems.push(error.message().to_string());
+                }
+            }
+        }
+        Err(Self::report(path.as_deref(), &problems))
+    }
+
+    /// The error listing every problem found in the configuration
+    fn report(path: Option<&Path>, problems: &[String]) -> ApplicationError {
+        let name = match path {
+            Some(path) => format!("the configuration file {}", path.display()),
+            None => "the configuration".to_string(),
+        };
+        let problems: Vec<String> = problems
+            .iter()
+            .map(|problem| format!("\n  - {}", problem))
+            .collect();
+        ApplicationError::configuration(format!("{} is invalid:{}", name, problems.concat()))
+    }
+
+    /// The settings of a profile of the document read from the given path
+    fn select<'a>(
+        document: &'a Value,
+        name: &str,
+        path: &Path,
+    ) -> Result<&'a Value, ApplicationError> {
+        let profiles = document.get("profiles").and_then(Value::as_mapping);
+        if let Some(profile) = profiles.and_then(|profiles| profiles.get(&Value::from(name))) {
+            return Ok(profile);
+        }
+        let available: Vec<&str> = profiles
+            .map(|profiles| {
+                profiles
+                    .iter()
+                    .filter_map(|(name, _)| name.as_str())
+                    .collect()
+            })
+            .unwrap_or_default();
+        Err(ApplicationError::configuration(format!(
+            "no profile {} in {}, the profiles are: {}",
+            name,
+            path.display(),
+            if available.is_empty() {
+                "none".to_string()
+            } else {
+                available.join(", ")
+            }
+        )))
     }
 
     /// Get the location of the first found default config file paths
     /// according to the following order:
     ///
     /// 1. $XDG_CONFIG_HOME/tracer/tracer.yml
-    /// 2. $XDG_CONFIG_HOME/tracer.yml
-    /// 3. $HOME/.config/tracer/tracer.yml
-    /// 4. $HOME/.tracer.yml
-    #[cfg(not(windows))]
+    /// 2. $XDG_CONFIG_DIRS/tracer/tracer.yml
+    /// 3. $XDG_CONFIG_HOME/tracer.yml
+    /// 4. $XDG_CONFIG_DIRS/tracer.yml
+    /// 5. $HOME/.config/tracer/tracer.yml
+    /// 6. $HOME/.tracer.yml
     pub fn find_configuration_file(&self) -> Option<PathBuf> {
-        // Try using XDG location by default.
-        xdg::BaseDirectories::with_prefix("tracer")
-            .ok()
-            .and_then(|xdg| xdg.find_config_file("tracer.yml"))
-            .or_else(|| {
-                xdg::BaseDirectories::new()
-                    .ok()
-                    .and_then(|fallback| fallback.find_config_file("tracer.yml"))
-            })
-            .or_else(|| {
-                if let Ok(home) = env::var("HOME") {
-                    let fallback = PathBuf::from(&home).join(".config/tracer/tracer.yml");
-                    if fallback.exists() {
-                        return Some(fallback);
-                    }
-                    let fallback = PathBuf::from(&home).join(".tracer.yml");
-                    if fallback.exists() {
-                        return Some(fallback);
-                    }
+        self.candidates().into_iter().find(|path| path.is_file())
+    }
+
+    /// The paths a configuration file is searched at, in order
+    #[cfg(not(windows))]
+    pub fn candidates(&self) -> Vec<PathBuf> {
+        let mut candidates = vec![];
+        for prefix in ["tracer", ""] {
+            if let Ok(xdg) = xdg::BaseDirectories::with_prefix(prefix) {
+                candidates.push(xdg.get_config_home().join("tracer.yml"));
+                for directory in xdg.get_config_dirs() {
+                    candidates.push(directory.join("tracer.yml"));
                 }
-                None
-            })
+            }
+        }
+        if let Ok(home) = env::var("HOME") {
+            candidates.push(PathBuf::from(&home).join(".config/tracer/tracer.yml"));
+            candidates.push(PathBuf::from(&home).join(".tracer.yml"));
+        }
+        let mut seen = HashSet::new();
+        candidates.retain(|candidate| seen.insert(candidate.clone()));
+        candidates
     }
 
     #[cfg(windows)]
-    pub fn find_configuration_file(&self) -> Option<PathBuf> {
+    pub fn candidates(&self) -> Vec<PathBuf> {
         dirs::config_dir()
             .map(|path| path.join("tracer\\tracer.yml"))
-            .filter(|new| new.exists())
+            .into_iter()
+            .collect()
     }
 }
 
 #[cfg(test)]
 mod tests {
-    use crate::common::Configuration;
-
     use super::*;
 
     #[test]
     fn config_read_eof() {
-        let parser = ConfigurationParser::new();
+        let parser = ConfigurationParser::with_path("config.example.yml");
         let configuration = parser.parse().unwrap();
         let host = configuration.environment().host();
-        println!("host = {:?}", host);
+        assert_eq!(host, "localhost:8080");
+    }
+
+    #[test]
+    fn config_overridden_by_variables() {
+        let parser = ConfigurationParser::with_path("config.example.yml")
+            .variables([("TRACER_HOST".to_string(), "example.com".to_string())])
+            .set("https", "true", Source::Argument("--set".into()));
+        let configuration = parser.parse().unwrap();
+        let environment = configuration.environment();
+        assert_eq!(environment.host(), "example.com");
+        assert_eq!(environment.ws_url(), "wss://example.com");
+    }
+
+    #[test]
+    fn config_profiles() {
+        let path = std::env::temp_dir().join(format!("tracer-profiles-{}.yml", std::process::id()));
+        std::fs::write(
+            &path,
+            r#"
+                environment:
+                    host: localhost:8080
+                    token: a super long token
+                    logging: DEBUG
+                default_profile: staging
+                profiles:
+                    staging:
+                        host: staging.example.com
+                    production:
+                        host: tracer.example.com
+                        https: true
+                        token: production token
+            "#,
+        )
+        .unwrap();
+
+        let default = ConfigurationParser::with_path(&path).variables([]).parse();
+        let production = ConfigurationParser::with_path(&path)
+            .variables([])
+            .profile("production")
+            .parse();
+        let unknown = ConfigurationParser::with_path(&path)
+            .variables([])
+            .profile("qa")
+            .parse();
+        std::fs::remove_file(&path).unwrap();
+
+        let default = default.unwrap();
+        assert_eq!(default.profile(), Some("staging"));
+        assert_eq!(default.environment().ws_url(), "ws://staging.example.com");
+        let production = production.unwrap();
+        assert_eq!(
+            production.environment().ws_url(),
+            "wss://tracer.example.com"
+        );
+        assert_eq!(
+            production.environment().token().unwrap().expose(),
+            "production token"
+        );
+        assert_eq!(production.environment().logging(), "DEBUG");
+        assert_eq!(
+            production.profiles(),
+            [
+                ("production", "tracer.example.com"),
+                ("staging", "staging.example.com")
+            ]
+        );
+        assert!(unknown.is_err());
+    }
+
+    #[test]
+    fn config_missing_path() {
+        let parser = ConfigurationParser::with_path("does-not-exist.yml");
+        assert!(parser.parse().is_err());
+    }
+
+    #[test]
+    fn config_problems_reported_together() {
+        let path = std::env::temp_dir().join(format!("tracer-invalid-{}.yml", std::process::id()));
+        std::fs::write(
+            &path,
+            r#"
+                environment:
+                    host: https://tracer.example.com
+                    logging: LOUD
+                    reconnect:
+                        max_delay: soon
+                        max_dleay: 60
+            "#,
+        )
+        .unwrap();
+
+        let error = ConfigurationParser::with_path(&path)
+            .variables([])
+            .parse()
+            .unwrap_err();
+        std::fs::remove_file(&path).unwrap();
+
+        let problems: Vec<&str> = error.message().lines().skip(1).collect();
+        assert_eq!(
+            problems,
+            [
+                "  - invalid environment.reconnect.max_delay, expected a positive number",
+                "  - unknown key environment.reconnect.max_dleay, did you mean environment.reconnect.max_delay?",
+                "  - invalid host https://tracer.example.com, leave out the scheme and set https: true for https",
+                "  - invalid logging level LOUD, expected one of OFF, ERROR, WARN, INFO, DEBUG or TRACE",
+                "  - no token is configured, set one of token, token_file, token_env or token_command",
+            ]
+        );
+    }
+
+    #[test]
+    fn config_yaml_error_located() {
+        let path = std::env::temp_dir().join(format!("tracer-yaml-{}.yml", std::process::id()));
+        std::fs::write(&path, "environment:\n  host: localhost\n   token: x\n").unwrap();
+
+        let error = ConfigurationParser::with_path(&path).parse().unwrap_err();
+        std::fs::remove_file(&path).unwrap();
+
+        assert!(error
+            .message()
+            .starts_with(&format!("{}:3:", path.display())));
     }
 }
@@ -0,0 +1,179 @@
+use std::collections::HashMap;
+use std::fmt;
+use std::fs;
+use std::os::unix::fs::PermissionsExt;
+use std::path::Path;
+use std::process::Command;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+use serde::{Deserialize, Serialize, Serializer};
+
+use crate::cmd;
+use crate::error::ApplicationError;
+
+/// What a secret is shown as
+const MASK: &str = "********";
+
+/// The tokens printed by commands, by command, along with when they expire
+static COMMAND_TOKENS: Mutex<Option<HashMap<String, (Secret, Instant)>>> = Mutex::new(None);
+
+/// A value which is masked whenever it is printed or serialised, so that it
+/// does not end up in logs
+#[derive(Clone, PartialEq, Eq, Deserialize)]
+#[serde(transparent)]
+pub struct Secret(String);
+
+impl Secret {
+    pub fn new(value: impl Into<String>) -> Self {
+        Self(value.into())
+    }
+
+    /// The actual value, to be handed to whatever needs it and nothing else
+    pub fn expose(&self) -> &str {
+        &self.0
+    }
+}
+
+impl fmt::Debug for Secret {
+    fn fmt(&self, formatter: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(formatter, "{}", MASK)
+    }
+}
+
+impl fmt::Display for Secret {
+    fn fmt(&self, formatter: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(formatter, "{}", MASK)
+    }
+}
+
+impl Serialize for Secret {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.serialize_str(MASK)
+    }
+}
+
+/// Reads a token from a file, warning when others than its owner can read it
+pub fn from_file(path: &Path) -> Result<Secret, ApplicationError> {
+    let error = |error: std::io::Error| {
+        ApplicationError::configuration(format!(
+            "unable to read the token file {}: {}",
+            path.display(),
+            error
+        ))
+    };
+    let token = fs::read_to_string(path).map_err(error)?;
+    if let Some(warning) = file_warning(path) {
+        warn!("{}", warning);
+    }
+    non_empty(token.trim(), || {
+        format!("the token file {}", path.display())
+    })
+}
+
+/// Warns about a token file which others than its owner can read
+pub fn file_warning(path: &Path) -> Option<String> {
+    let mode = fs::metadata(path).ok()?.permissions().mode();
+    if mode & 0o044 == 0 {
+        return None;
+    }
+    Some(format!(
+        "the token file {} can be read by others, restrict it with chmod 600",
+        path.display()
+    ))
+}
+
+/// Reads a token from an environment variable
+pub fn from_variable(name: &str) -> Result<Secret, ApplicationError> {
+    let token = std::env::var(name).map_err(|_| {
+        ApplicationError::configuration(format!("the token variable {} is not set", name))
+    })?;
+    non_empty(token.trim(), || format!("the token variable {}", name))
+}
+
+/// Runs a command printing the token, reusing what it printed until the time
+/// to live expires
+pub fn from_command(command: &str, ttl: Duration) -> Result<Secret, ApplicationError> {
+    let mut tokens = COMMAND_TOKENS
+        .lock()
+        .unwrap_or_else(|error| error.into_inner());
+    let tokens = tokens.get_or_insert_with(HashMap::new);
+    if let Some((token, expires)) = tokens.get(command) {
+        if Instant::now() < *expires {
+            return Ok(token.clone());
+        }
+    }
+    debug!("running the token command");
+    let output = cmd::output(Command::new("sh").args(["-c", command])).map_err(|error| {
+        ApplicationError::configuration(format!("unable to run the token command: {}", error))
+    })?;
+    if !output.status.success() {
+        return Err(ApplicationError::configuration(format!(
+            "the token command failed with {}: {}",
+            output.status,
+            String::from_utf8_lossy(&output.stderr).trim()
+        )));
+    }
+    let token = String::from_utf8(output.stdout)
+        .map_err(|_| ApplicationError::configuration("the token command printed invalid UTF-8"))?;
+    let token = non_empty(token.trim(), || "the token command".to_string())?;
+    tokens.insert(command.to_string(), (token.clone(), Instant::now() + ttl));
+    Ok(token)
+}
+
+fn non_empty(token: &str, origin: impl Fn() -> String) -> Result<Secret, ApplicationError> {
+    if token.is_empty() {
+        return Err(ApplicationError::configuration(format!(
+            "{} holds an empty token",
+            origin()
+        )));
+    }
+    Ok(Secret::new(token))
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_secret_is_masked() {
+        let secret = Secret::new("a super long token");
+
+        assert_eq!(format!("{:?}", secret), MASK);
+        assert_eq!(secret.to_string(), MASK);
+        assert_eq!(serde_json::to_string(&secret).unwrap(), "\"********\"");
+        assert_eq!(secret.expose(), "a super long token");
+    }
+
+    #[test]
+    fn test_file_readable_by_others() {
+        let path = std::env::temp_dir().join(format!("tracer-token-file-{}", std::process::id()));
+        fs::write(&path, "token\n").unwrap();
+        let warning = |mode| {
+            fs::set_permissions(&path, fs::Permissions::from_mode(mode)).unwrap();
+            file_warning(&path)
+        };
+
+        assert!(warning(0o600).is_none());
+        assert!(warning(0o620).is_none());
+        assert!(warning(0o640).is_some());
+        assert!(warning(0o604).is_some());
+        assert_eq!(from_file(&path).unwrap().expose(), "token");
+        fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn test_command_token_is_cached() {
+        let path = std::env::temp_dir().join(format!("tracer-token-{}", std::process::id()));
+        let command = format!("echo run >> {}; echo ' token '", path.display());
+
+        let first = from_command(&command, Duration::from_secs(60)).unwrap();
+        let second = from_command(&command, Duration::from_secs(60)).unwrap();
+        let runs = fs::read_to_string(&path).unwrap();
+        fs::remove_file(&path).unwrap();
+
+        assert_eq!(first.expose(), "token");
+        assert_eq!(second, first);
+        assert_eq!(runs.lines().count(), 1);
+    }
+}
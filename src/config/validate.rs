@@ -0,0 +1,282 @@
+use std::path::Path;
+use std::str::FromStr;
+
+use serde_yaml::Value;
+
+use crate::config::layers::{collect, lookup, Kind, Setting, SETTINGS, TOKEN_SOURCES};
+
+/// The keys of a configuration file
+const KEYS: &[&str] = &["environment", "commands", "profiles", "default_profile"];
+
+/// The keys of a command defined in a configuration file
+const COMMAND_KEYS: &[&str] = &[
+    "executable",
+    "args",
+    "env",
+    "working_directory",
+    "timeout",
+    "idle_timeout",
+    "tty",
+    "capture",
+    "tags",
+];
+
+/// The keys of a configuration file which tracer does not know of, by dotted
+/// path and with the key that was probably meant, and the settings which do
+/// not have the right type
+pub fn keys(document: &Value) -> Vec<String> {
+    let mut problems = vec![];
+    let document = match document.as_mapping() {
+        Some(document) => document,
+        None => return problems,
+    };
+    for (key, value) in document {
+        let key = match key.as_str() {
+            Some(key) => key,
+            None => continue,
+        };
+        if !KEYS.contains(&key) {
+            problems.push(unknown("", key, KEYS));
+            continue;
+        }
+        match key {
+            "environment" => problems.extend(settings(value, "environment")),
+            "profiles" => {
+                for (name, profile) in value.as_mapping().into_iter().flatten() {
+                    if let Some(name) = name.as_str() {
+                        problems.extend(settings(profile, &format!("profiles.{}", name)));
+                    }
+                }
+            }
+            "commands" => {
+                for (name, command) in value.as_mapping().into_iter().flatten() {
+                    let keys = command.as_mapping().into_iter().flatten();
+                    for key in keys.filter_map(|(key, _)| key.as_str()) {
+                        if !COMMAND_KEYS.contains(&key) {
+                            let prefix = format!("commands.{}", name.as_str().unwrap_or("?"));
+                            problems.push(unknown(&prefix, key, COMMAND_KEYS));
+                        }
+                    }
+                }
+            }
+            _ => {}
+        }
+    }
+    problems
+}
+
+/// The settings of an environment, or of a profile, which are not known or
+/// are not of the type of the setting
+fn settings(environment: &Value, prefix: &str) -> Vec<String> {
+    let mut leaves = vec![];
+    collect(environment, String::new(), &mut leaves);
+    let keys: Vec<&str> = SETTINGS.iter().map(|setting| setting.key).collect();
+    let mut problems = vec![];
+    for (key, value) in leaves {
+        match Setting::find(&key) {
+            Some(setting) => {
+                let expected = match setting.kind {
+                    Kind::Text | Kind::Secret => None,
+                    Kind::Flag if !value.is_bool() => Some("true or false"),
+                    Kind::Number if !value.is_u64() => Some("a positive number"),
+                    Kind::Flag | Kind::Number => None,
+                };
+                if let Some(expected) = expected {
+                    problems.push(format!("invalid {}.{}, expected {}", prefix, key, expected));
+                }
+            }
+            // a section given as a value is left to deserialising to report
+            None if keys
+                .iter()
+                .any(|known| known.starts_with(&format!("{}.", key))) => {}
+            None => problems.push(unknown(prefix, &key, &keys)),
+        }
+    }
+    problems
+}
+
+/// Describes an unknown key found within the given dotted path
+fn unknown(prefix: &str, key: &str, known: &[&str]) -> String {
+    let path = |key: &str| {
+        if prefix.is_empty() {
+            key.to_string()
+        } else {
+            format!("{}.{}", prefix, key)
+        }
+    };
+    match suggest(key, known) {
+        Some(known) => format!("unknown key {}, did you mean {}?", path(key), path(known)),
+        None => format!("unknown key {}", path(key)),
+    }
+}
+
+/// The known key closest to a misspelt one, if any is close enough
+fn suggest<'a>(key: &str, known: &[&'a str]) -> Option<&'a str> {
+    known
+        .iter()
+        .map(|candidate| (distance(key, candidate), *candidate))
+        .filter(|(distance, _)| *distance <= 2)
+        .min_by_key(|(distance, _)| *distance)
+        .map(|(_, candidate)| candidate)
+}
+
+/// The number of characters to insert, delete or substitute to turn one
+/// string into the other
+fn distance(a: &str, b: &str) -> usize {
+    let b: Vec<char> = b.chars().collect();
+    let mut previous: Vec<usize> = (0..=b.len()).collect();
+    for (i, a) in a.chars().enumerate() {
+        let mut current = vec![i + 1];
+        for (j, b) in b.iter().enumerate() {
+            let substitution = previous[j] + usize::from(a != *b);
+            current.push(substitution.min(previous[j + 1] + 1).min(current[j] + 1));
+        }
+        previous = current;
+    }
+    previous[b.len()]
+}
+
+/// What is wrong with a host, which is given without a scheme or a path,
+/// e.g. `tracer.example.com:8080`
+pub fn host(host: &str) -> Option<String> {
+    if host.is_empty() {
+        return Some("the host is empty".to_string());
+    }
+    if host.contains("://") {
+        return Some(format!(
+            "invalid host {}, leave out the scheme and set https: true for https",
+            host
+        ));
+    }
+    match url::Url::parse(&format!("http://{}", host)) {
+        Ok(url) if url.path() == "/" && !host.ends_with('/') && url.query().is_none() => None,
+        Ok(_) => Some(format!(
+            "invalid host {}, give the host and port only, e.g. tracer.example.com:8080",
+            host
+        )),
+        Err(error) => Some(format!("invalid host {}: {}", host, error)),
+    }
+}
+
+/// Describes a YAML error with the path, line and column it was found at
+pub fn yaml_error(path: &Path, error: &serde_yaml::Error) -> String {
+    match error.location() {
+        Some(location) => format!(
+            "{}:{}:{}: {}",
+            path.display(),
+            location.line(),
+            location.column(),
+            without_location(error)
+        ),
+        None => format!("{}: {}", path.display(), error),
+    }
+}
+
+/// The message of a YAML error, without the line and column it was found at
+pub fn without_location(error: &serde_yaml::Error) -> String {
+    let message = error.to_string();
+    match error.location() {
+        Some(location) => {
+            let suffix = format!(" at line {} column {}", location.line(), location.column());
+            message
+                .strip_suffix(&suffix)
+                .map(str::to_string)
+                .unwrap_or(message)
+        }
+        None => message,
+    }
+}
+
+/// What is wrong with the settings of an environment that deserialising
+/// does not check
+pub fn environment(environment: &Value) -> Vec<String> {
+    let mut problems = vec![];
+    match lookup(environment, "host").and_then(Value::as_str) {
+        Some(value) => problems.extend(host(value)),
+        None => problems.push("the host is not set".to_string()),
+    }
+    if let Some(logging) = lookup(environment, "logging").and_then(Value::as_str) {
+        if log::LevelFilter::from_str(logging).is_err() {
+            problems.push(format!(
+                "invalid logging level {}, expected one of OFF, ERROR, WARN, INFO, DEBUG or TRACE",
+                logging
+            ));
+        }
+    }
+    for key in [
+        "heartbeat.interval",
+        "heartbeat.pong_timeout",
+        "heartbeat.max_missed",
+    ] {
+        if lookup(environment, key).and_then(Value::as_u64) == Some(0) {
+            problems.push(format!("{} must be greater than 0", key));
+        }
+    }
+    let sources = TOKEN_SOURCES
+        .iter()
+        .filter(|key| !matches!(lookup(environment, key), None | Some(Value::Null)))
+        .count();
+    match sources {
+        0 => problems.push(
+            "no token is configured, set one of token, token_file, token_env or token_command"
+                .to_string(),
+        ),
+        1 => {}
+        _ => problems.push(
+            "only one of token, token_file, token_env and token_command can be set".to_string(),
+        ),
+    }
+    problems
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_keys() {
+        let document: Value = serde_yaml::from_str(
+            r#"
+                environment:
+                    hots: localhost:8080
+                    token: a super long token
+                    https: yes please
+                    reconnect:
+                        max_dleay: 60
+                        colour: blue
+                profiles:
+                    staging:
+                        loging: DEBUG
+                commands:
+                    backup:
+                        timout: 60
+                defaults: {}
+            "#,
+        )
+        .unwrap();
+
+        assert_eq!(
+            keys(&document),
+            [
+                "unknown key environment.hots, did you mean environment.host?",
+                "invalid environment.https, expected true or false",
+                "unknown key environment.reconnect.max_dleay, did you mean environment.reconnect.max_delay?",
+                "unknown key environment.reconnect.colour",
+                "unknown key profiles.staging.loging, did you mean profiles.staging.logging?",
+                "unknown key commands.backup.timout, did you mean commands.backup.timeout?",
+                "unknown key defaults",
+            ]
+        );
+    }
+
+    #[test]
+    fn test_host() {
+        assert!(host("localhost:8080").is_none());
+        assert!(host("tracer.example.com").is_none());
+        assert!(host("").is_some());
+        assert!(host("https://tracer.example.com").is_some());
+        assert!(host("tracer.example.com/api").is_some());
+        assert!(host("tracer example.com").is_some());
+        assert!(host("localhost:port").is_some());
+    }
+}
@@ -42,6 +42,11 @@ impl ApplicationError {
     pub fn kind(&self) -> &ApplicationErrorKind {
         &self.kind
     }
+
+    /// The explanation of the error, without its kind
+    pub fn message(&self) -> &str {
+        &self.message
+    }
 }
 
 impl fmt::Display for ApplicationError {
@@ -92,7 +97,7 @@ impl From<InvalidHeaderValue> for ApplicationError {
 
 impl From<reqwest::Error> for ApplicationError {
     fn from(error: reqwest::Error) -> Self {
-        ApplicationError::io(format!("{}", error))
+        ApplicationError::transport(format!("{}", error))
     }
 }
 
@@ -137,6 +142,15 @@ impl From<ParseLevelError> for ApplicationError {
     }
 }
 
+/// The kind of failure tracer ran into. Each kind has its own process exit
+/// code so that scripts can tell tracer's failures apart from the command's:
+///
+/// | kind          | exit code |
+/// |---------------|-----------|
+/// | transport     | 69        |
+/// | io            | 74        |
+/// | configuration | 78        |
+/// | command       | 127       |
 #[derive(Clone, Copy, Debug)]
 pub enum ApplicationErrorKind {
     Io,
@@ -145,6 +159,18 @@ pub enum ApplicationErrorKind {
     Configuration,
 }
 
+impl ApplicationErrorKind {
+    /// The process exit code used when tracer fails with this kind of error
+    pub fn exit_code(&self) -> i32 {
+        match self {
+            ApplicationErrorKind::Transport => 69,
+            ApplicationErrorKind::Io => 74,
+            ApplicationErrorKind::Configuration => 78,
+            ApplicationErrorKind::Command => 127,
+        }
+    }
+}
+
 impl Display for ApplicationErrorKind {
     fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
         let string = match self {
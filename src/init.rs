@@ -0,0 +1,159 @@
+use std::fs::{self, OpenOptions};
+use std::io::{self, BufRead, Write};
+use std::os::unix::fs::{OpenOptionsExt, PermissionsExt};
+use std::path::{Path, PathBuf};
+
+use serde_yaml::{Mapping, Value};
+
+use crate::cli::InitOptions;
+use crate::config::configuration::Environment;
+use crate::config::{Configuration, ConfigurationParser};
+use crate::error::ApplicationError;
+use crate::services::ApiClient;
+
+/// Creates the configuration file at the given path, or where it is searched
+/// first, prompting for the host and the token when they are not given. The
+/// server is asked to list the commands to check it accepts the token before
+/// anything is written.
+pub async fn init(options: &InitOptions, path: Option<&Path>) -> Result<PathBuf, ApplicationError> {
+    let path = path
+        .map(Path::to_path_buf)
+        .or_else(|| ConfigurationParser::new().candidates().into_iter().next())
+        .ok_or_else(|| {
+            ApplicationError::configuration("unable to tell where to create the configuration file")
+        })?;
+    if path.exists() && !options.force {
+        return Err(ApplicationError::configuration(format!(
+            "the configuration file {} already exists, give --force to overwrite it",
+            path.display()
+        )));
+    }
+    let text = serde_yaml::to_string(&document(options)?)?;
+    let text = format!(
+        "# created by tracer init, config.example.yml describes every setting\n{}",
+        text.trim_start_matches("---\n")
+    );
+    let configuration: Configuration = text.parse()?;
+    configuration.environment().validate()?;
+    if !options.no_verify {
+        verify(configuration.environment()).await?;
+    }
+    write(&path, &text)?;
+    Ok(path)
+}
+
+/// The configuration with the settings given as options, or answered
+fn document(options: &InitOptions) -> Result<Value, ApplicationError> {
+    let interactive = unsafe { libc::isatty(libc::STDIN_FILENO) } == 1;
+    let mut environment = Mapping::new();
+    let mut set = |key: &str, value: Value| {
+        environment.insert(Value::from(key), value);
+    };
+    match &options.host {
+        Some(host) => {
+            set("host", Value::from(host.as_str()));
+            set("https", Value::from(options.https));
+        }
+        None if interactive => {
+            set("host", Value::from(ask("Host and port of the server")?));
+            let https = ask("Connect over https? [y/N]")?;
+            set("https", Value::from(https.eq_ignore_ascii_case("y")));
+        }
+        None => return Err(ApplicationError::configuration("give the host with --host")),
+    }
+    if let Some(token) = &options.token {
+        set("token", Value::from(token.as_str()));
+    } else if let Some(path) = &options.token_file {
+        set("token_file", Value::from(path.to_string_lossy().as_ref()));
+    } else if let Some(name) = &options.token_env {
+        set("token_env", Value::from(name.as_str()));
+    } else if let Some(command) = &options.token_command {
+        set("token_command", Value::from(command.as_str()));
+    } else if interactive {
+        set("token", Value::from(ask_secret("API token")?));
+    } else {
+        return Err(ApplicationError::configuration(
+            "give the token with --token, --token-file, --token-env or --token-command",
+        ));
+    }
+    set("logging", Value::from("INFO"));
+    let mut document = Mapping::new();
+    document.insert(Value::from("environment"), Value::Mapping(environment));
+    Ok(Value::Mapping(document))
+}
+
+/// Asks a question on the terminal and reads the answer
+fn ask(question: &str) -> Result<String, ApplicationError> {
+    eprint!("{}: ", question);
+    io::stderr().flush()?;
+    let mut answer = String::new();
+    io::stdin().lock().read_line(&mut answer)?;
+    Ok(answer.trim().to_string())
+}
+
+/// Asks a question on the terminal without echoing the answer
+fn ask_secret(question: &str) -> Result<String, ApplicationError> {
+    let mut terminal = unsafe { std::mem::zeroed::<libc::termios>() };
+    let hidden = unsafe { libc::tcgetattr(libc::STDIN_FILENO, &mut terminal) } == 0;
+    if hidden {
+        let mut quiet = terminal;
+        quiet.c_lflag &= !libc::ECHO;
+        unsafe { libc::tcsetattr(libc::STDIN_FILENO, libc::TCSANOW, &quiet) };
+    }
+    let answer = ask(question);
+    if hidden {
+        unsafe { libc::tcsetattr(libc::STDIN_FILENO, libc::TCSANOW, &terminal) };
+        eprintln!();
+    }
+    answer
+}
+
+/// Checks the server can be reached and accepts the token
+async fn verify(environment: &Environment) -> Result<(), ApplicationError> {
+    let token = environment.token()?;
+    let client = ApiClient::new(&environment.api_url(), token.expose())?;
+    match client.commands().await {
+        Ok(Some(commands)) => {
+            println!(
+                "connected to {}, {} commands registered",
+                environment.host(),
+                commands.len()
+            );
+            Ok(())
+        }
+        Ok(None) => Err(ApplicationError::configuration(format!(
+            "the server at {} rejected the token, give --no-verify to write the configuration anyway",
+            environment.host()
+        ))),
+        Err(error) => Err(ApplicationError::transport(format!(
+            "unable to check the token with the server at {}: {}, give --no-verify to write the configuration anyway",
+            environment.host(),
+            error.message()
+        ))),
+    }
+}
+
+/// Writes the configuration file so that only its owner can read it
+fn write(path: &Path, text: &str) -> Result<(), ApplicationError> {
+    let error = |error: io::Error| {
+        ApplicationError::io(format!(
+            "unable to write the configuration file {}: {}",
+            path.display(),
+            error
+        ))
+    };
+    if let Some(directory) = path.parent() {
+        fs::create_dir_all(directory).map_err(error)?;
+    }
+    let mut file = OpenOptions::new()
+        .write(true)
+        .create(true)
+        .truncate(true)
+        .mode(0o600)
+        .open(path)
+        .map_err(error)?;
+    // the mode is only given to files which did not exist
+    file.set_permissions(fs::Permissions::from_mode(0o600))
+        .map_err(error)?;
+    file.write_all(text.as_bytes()).map_err(error)
+}
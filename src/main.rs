@@ -7,11 +7,13 @@ use signal_hook_tokio::Signals;
 use tokio::sync::mpsc;
 use tokio::sync::mpsc::UnboundedSender;
 
-use crate::application::Application;
-use crate::cmd::Command;
-use crate::config::ConfigurationParser;
+use crate::application::{Application, Loader};
+use crate::cli::{Action, Cli, ConfigAction};
+use crate::cmd::ExitStatus;
+use crate::common::ApplicationConfig;
+use crate::config::configuration::Environment;
+use crate::config::{Configuration, ConfigurationParser, Source};
 use crate::error::ApplicationError;
-use crate::services::Logging;
 
 mod application;
 mod cli;
@@ -20,83 +22,156 @@ mod common;
 mod config;
 mod error;
 mod http;
+mod init;
 mod manager;
 mod services;
+mod spool;
 mod ws;
 
-async fn handle_signals(sender: UnboundedSender<()>, mut signals: Signals) {
+async fn handle_signals(
+    shutdown: UnboundedSender<()>,
+    reload: UnboundedSender<()>,
+    mut signals: Signals,
+) {
     while let Some(signal) = signals.next().await {
         match signal {
             SIGHUP => {
-                println!("hello sigup!");
-                // Reload configuration
-                // Reopen the log file
+                info!("received SIGHUP, reloading the configuration");
+                let _ = reload.send(());
             }
             SIGTERM | SIGINT | SIGQUIT => {
-                println!("Shutting down system");
-                sender.send(()).unwrap();
-                // Shutdown the system;
+                info!("received signal {}, terminating the command", signal);
+                // the application may already be done with the command
+                let _ = shutdown.send(());
             }
             _ => unreachable!(),
         }
     }
 }
 
-#[tokio::main]
-async fn main() -> Result<(), ApplicationError> {
-    let parser = ConfigurationParser::new();
+/// The parser of the configuration with the overrides of the command line
+fn parser(cli: &Cli) -> ConfigurationParser {
+    let mut parser = match &cli.config {
+        Some(path) => ConfigurationParser::with_path(path),
+        None => ConfigurationParser::new(),
+    };
+    if let Some(profile) = &cli.profile {
+        parser = parser.profile(profile);
+    }
+    if let Some(level) = &cli.log_level {
+        parser = parser.set("logging", level, Source::Argument("--log-level".into()));
+    }
+    for (key, value) in &cli.set {
+        parser = parser.set(key, value, Source::Argument("--set".into()));
+    }
+    parser
+}
+
+async fn run(cli: Cli) -> Result<ExitStatus, ApplicationError> {
+    let parser = parser(&cli);
+    // the configuration is created rather than read
+    if let Action::Init(options) = &cli.action {
+        let path = init::init(options, cli.config.as_deref()).await?;
+        println!("created the configuration file {}", path.display());
+        return Ok(ExitStatus::new(Some(0), None));
+    }
     let configuration = parser.parse()?;
-    let environment = configuration.environment();
+    match cli.action {
+        Action::Run(options) => {
+            let config = options.config(&configuration)?;
+            run_command(configuration.environment().to_owned(), parser, config).await
+        }
+        Action::Config(ConfigAction::Show) => {
+            show(&configuration);
+            Ok(ExitStatus::new(Some(0), None))
+        }
+        Action::Config(ConfigAction::Profiles) => {
+            profiles(&configuration);
+            Ok(ExitStatus::new(Some(0), None))
+        }
+        // parsing already failed with the problems of an invalid configuration
+        Action::Config(ConfigAction::Validate) => {
+            // logging is not started, warnings would go unnoticed otherwise
+            for warning in configuration.environment().warnings() {
+                eprintln!("warning: {}", warning);
+            }
+            match configuration.path() {
+                Some(path) => println!("the configuration file {} is valid", path.display()),
+                None => println!("the configuration is valid"),
+            }
+            Ok(ExitStatus::new(Some(0), None))
+        }
+        Action::Init(_) => unreachable!("the configuration is created before it is read"),
+    }
+}
+
+async fn run_command(
+    environment: Environment,
+    parser: ConfigurationParser,
+    config: ApplicationConfig,
+) -> Result<ExitStatus, ApplicationError> {
+    let loader: Loader = Box::new(move || Ok(parser.parse()?.environment().to_owned()));
     let (shutdown_send, shutdown_recv) = mpsc::unbounded_channel();
-    let command = Command::new("./examples/count.sh").args(&["5"]);
-    let application = Application::new(environment.to_owned(), shutdown_recv);
+    let (reload_send, reload_recv) = mpsc::unbounded_channel();
+    let mut application = Application::new(environment, shutdown_recv, reload_recv, loader);
+
+    // handle shutdown and reload signals...
+    let signals = Signals::new([SIGHUP, SIGTERM, SIGINT, SIGQUIT])?;
+    let handle = signals.handle();
+    let signals_task = tokio::spawn(handle_signals(shutdown_send, reload_send, signals));
+
+    let result = application.run(config).await;
+    let stopped = application.shutdown().await;
+
+    handle.close();
+    signals_task.await?;
 
-    // handle shutdown signals...
-    let signals = Signals::new(&[SIGHUP, SIGTERM, SIGINT, SIGQUIT])?;
+    let status = result?;
+    stopped?;
+    Ok(status)
+}
+
+/// Prints the effective settings and where they come from
+fn show(configuration: &Configuration) {
+    if let Some(profile) = configuration.profile() {
+        println!("{:<24} {}", "profile", profile);
+    }
+    for (key, value, source) in configuration.settings() {
+        println!("{:<24} {:<32} {}", key, value, source);
+    }
+}
 
-    //
-    // let handle = signals.handle();
-    //
-    let signals_task = tokio::spawn(handle_signals(shutdown_send.clone(), signals));
-    //
-    application.run(command).await?;
-    //
-    // // info!("Spawning command");
-    // // let command = Command::new("./examples/count.sh");
-    // // let mut spawned = command.spawn().unwrap();
-    // // loop {
-    // //     tokio::select! {
-    // //         line = spawned.next() => {
-    // //             if let Some(line) = line {
-    // //                 info!("Got line: {:?}", line);
-    // //             }
-    // //
-    // //         }
-    // //
-    // //     }
-    // // }
-    //
-    //
-    //
-    // // let process = Process::new("./examples/count.sh");
-    // //
-    // // match process.run() {
-    // //     Ok(mut receiver) => {
-    // //         loop {
-    // //             tokio::select! {
-    // //                 line = receiver.recv() => {
-    // //                     if let Some(line) = line {
-    // //                         info!("Got line: {:?}", line);
-    // //                     }
-    // //                 }
-    // //             }
-    // //         }
-    // //     }
-    // //     Err(error) => error!("There was an error: {:?}", error)
-    // // }
-    //
-    // handle.close();
-    // signals_task.await?;
+/// Lists the profiles, marking the one in use
+fn profiles(configuration: &Configuration) {
+    for (name, host) in configuration.profiles() {
+        let marker = if configuration.profile() == Some(name) {
+            "*"
+        } else {
+            " "
+        };
+        let default = if configuration.default_profile() == Some(name) {
+            " (default)"
+        } else {
+            ""
+        };
+        println!("{} {:<22} {}{}", marker, name, host, default);
+    }
+}
 
-    Ok(())
+/// Exits with the status of the command, or with the exit code of the kind
+/// of error when tracer itself failed.
+#[tokio::main]
+async fn main() {
+    let cli = cli::parse();
+    let code = match run(cli).await {
+        Ok(status) => {
+            info!("command finished with {}", status);
+            status.exit_code()
+        }
+        Err(error) => {
+            eprintln!("Error: {}", error);
+            error.kind().exit_code()
+        }
+    };
+    std::process::exit(code);
 }
@@ -1,69 +1,984 @@
-use futures_util::StreamExt;
-use tokio::sync::mpsc::unbounded_channel;
+use std::time::Duration;
 
-use crate::cmd::Command;
+use tokio::sync::mpsc::{channel, Sender, UnboundedReceiver};
+use tokio::sync::watch;
+use tokio::time::{sleep_until, Instant};
+
+use crate::cmd::{Command, CommandChild, CommandEvent, ExitStatus, Signal, Timestamp, WindowSize};
+use crate::common::Session;
 use crate::config::configuration::Environment;
 use crate::error::ApplicationError;
-use crate::ws::{Packet, WebSocket, WebSocketRequest};
+use crate::spool::Spool;
+use crate::ws::{Connection, Encoding, Packet, Termination, TerminationReason, WebSocketRequest};
+
+/// The number of command events that can be buffered before the output
+/// handlers wait for the websocket to catch up
+const EVENT_BUFFER_SIZE: usize = 1024;
+
+/// How long to wait for the server to acknowledge the remaining output once
+/// the command exited before reporting the exit status regardless
+const ACK_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// A command being stopped
+struct Terminating {
+    reason: TerminationReason,
+    /// the signal sent last
+    signal: Signal,
+    /// when the command is killed if it is still running
+    kill_at: Instant,
+    escalated: bool,
+}
+
+impl Terminating {
+    fn kill_at(&self) -> Option<Instant> {
+        if self.signal == Signal::KILL {
+            return None;
+        }
+        Some(self.kill_at)
+    }
+
+    fn report(&self) -> Termination {
+        Termination {
+            reason: self.reason,
+            signal: self.signal.to_string(),
+            escalated: self.escalated,
+        }
+    }
+}
+
+/// The connection to the server the manager streams the output over, so that
+/// the manager can be driven without a server
+pub trait Link {
+    fn is_connected(&self) -> bool;
+
+    fn is_authenticated(&self) -> bool;
+
+    fn is_reconnecting(&self) -> bool;
+
+    fn retry_at(&self) -> Instant;
+
+    async fn next(&mut self) -> Option<Packet>;
+
+    async fn authenticated(&mut self);
+
+    async fn authentication_failed(&mut self) -> bool;
+
+    async fn send(&mut self, packet: &Packet) -> bool;
+
+    async fn pong(&mut self);
+
+    async fn reconnect(&mut self) -> Result<(), ApplicationError>;
+
+    async fn close(&mut self) -> Result<(), ApplicationError>;
+}
+
+impl Link for Connection {
+    fn is_connected(&self) -> bool {
+        Connection::is_connected(self)
+    }
+
+    fn is_authenticated(&self) -> bool {
+        Connection::is_authenticated(self)
+    }
+
+    fn is_reconnecting(&self) -> bool {
+        Connection::is_reconnecting(self)
+    }
+
+    fn retry_at(&self) -> Instant {
+        Connection::retry_at(self)
+    }
+
+    async fn next(&mut self) -> Option<Packet> {
+        Connection::next(self).await
+    }
+
+    async fn authenticated(&mut self) {
+        Connection::authenticated(self).await
+    }
+
+    async fn authentication_failed(&mut self) -> bool {
+        Connection::authentication_failed(self).await
+    }
+
+    async fn send(&mut self, packet: &Packet) -> bool {
+        Connection::send(self, packet).await
+    }
+
+    async fn pong(&mut self) {
+        Connection::pong(self).await
+    }
+
+    async fn reconnect(&mut self) -> Result<(), ApplicationError> {
+        Connection::reconnect(self).await
+    }
+
+    async fn close(&mut self) -> Result<(), ApplicationError> {
+        Connection::close(self).await
+    }
+}
+
+/// A command the manager launches, publishing its events on the given bus
+pub trait Launch: std::fmt::Debug {
+    type Child: Child;
+
+    fn max_duration(&self) -> Option<Duration>;
+
+    fn max_idle(&self) -> Option<Duration>;
+
+    fn spawn(self, bus: Sender<CommandEvent>) -> Result<Self::Child, ApplicationError>;
+}
+
+impl Launch for Command {
+    type Child = CommandChild;
+
+    fn max_duration(&self) -> Option<Duration> {
+        Command::max_duration(self)
+    }
+
+    fn max_idle(&self) -> Option<Duration> {
+        Command::max_idle(self)
+    }
+
+    fn spawn(self, bus: Sender<CommandEvent>) -> Result<CommandChild, ApplicationError> {
+        Command::spawn(self, bus)
+    }
+}
+
+/// A command the manager launched
+pub trait Child {
+    fn pid(&self) -> u32;
+
+    fn signal(&self, signal: Signal) -> Result<(), ApplicationError>;
+
+    fn kill(&self) -> Result<(), ApplicationError>;
+
+    fn write(&self, data: &[u8]) -> Result<(), ApplicationError>;
+
+    fn close_stdin(&mut self);
+
+    fn resize(&self, size: WindowSize) -> Result<(), ApplicationError>;
+}
+
+impl Child for CommandChild {
+    fn pid(&self) -> u32 {
+        CommandChild::pid(self)
+    }
+
+    fn signal(&self, signal: Signal) -> Result<(), ApplicationError> {
+        CommandChild::signal(self, signal)
+    }
+
+    fn kill(&self) -> Result<(), ApplicationError> {
+        CommandChild::kill(self)
+    }
+
+    fn write(&self, data: &[u8]) -> Result<(), ApplicationError> {
+        CommandChild::write(self, data)
+    }
+
+    fn close_stdin(&mut self) {
+        CommandChild::close_stdin(self)
+    }
+
+    fn resize(&self, size: WindowSize) -> Result<(), ApplicationError> {
+        CommandChild::resize(self, size)
+    }
+}
 
 pub struct Manager {
-    environment: Environment,
+    /// the environment, as reloaded while the command runs
+    environment: watch::Receiver<Environment>,
 }
 
 impl Manager {
-    pub fn new(environment: &Environment) -> Self {
-        Self {
-            environment: environment.to_owned(),
-        }
+    pub fn new(environment: watch::Receiver<Environment>) -> Self {
+        Self { environment }
     }
 
-    pub async fn create_websocket(&self, session_id: &str) -> Result<WebSocket, ApplicationError> {
-        let url = format!("{}/ws/sessions/{}", self.environment.ws_url(), session_id);
-        let request = WebSocketRequest::new(url, "token")?;
-        WebSocket::new(request).await
+    pub async fn create_connection(
+        &self,
+        session: &Session,
+    ) -> Result<Connection, ApplicationError> {
+        let environment = self.environment.borrow().clone();
+        let url = format!("{}/ws/sessions/{}", environment.ws_url(), session.id());
+        let request = WebSocketRequest::new(url, session.token());
+        Connection::open(
+            request,
+            session.token(),
+            environment.reconnect(),
+            environment.heartbeat(),
+        )
+        .await
     }
 
-    pub async fn spawn(&self, command: Command) -> Result<(), ApplicationError> {
+    /// Authenticates against the websocket of the session, launches the
+    /// command once the server accepts us and streams its output until it
+    /// exits, or stops it when the server or a local signal asks for it.
+    /// The connection is re-established whenever it is lost, while the
+    /// command keeps running and its output is spooled to disk. Output is
+    /// numbered and kept until the server acknowledges it, so that anything
+    /// unacknowledged is retransmitted after a reconnection.
+    /// The command is stopped the same way when it exceeds its timeout or
+    /// its idle timeout. A local shutdown stops forwarding input, terminates
+    /// the command and waits for its remaining output to be acknowledged, all
+    /// within the drain timeout.
+    pub async fn spawn(
+        &self,
+        session: &Session,
+        command: Command,
+        shutdown: &mut UnboundedReceiver<()>,
+    ) -> Result<ExitStatus, ApplicationError> {
         debug!("Spawning manager");
-        let (rx, mut tx) = unbounded_channel();
-        let mut websocket = self.create_websocket("123-123-123-123").await?;
+        let spool = Spool::open(
+            &Spool::path_for(session.id())?,
+            self.environment.borrow().spool(),
+        )?;
+        let mut connection = self.create_connection(session).await?;
+        self.run(&mut connection, spool, command, shutdown).await
+    }
+
+    /// Runs the command over the link until it exited and its output was
+    /// acknowledged, or until it was given up on
+    async fn run<C: Launch>(
+        &self,
+        connection: &mut impl Link,
+        mut spool: Spool,
+        command: C,
+        shutdown: &mut UnboundedReceiver<()>,
+    ) -> Result<ExitStatus, ApplicationError> {
+        let (sender, mut receiver) = channel(EVENT_BUFFER_SIZE);
+        let max_duration = command.max_duration();
+        let max_idle = command.max_idle();
+        let mut command = Some(command);
+        let mut child: Option<C::Child> = None;
+        let mut status: Option<ExitStatus> = None;
+        // the command terminated packet, once the command exited
+        let mut summary: Option<Packet> = None;
+        let mut sequence: u64 = 0;
+        // when to stop waiting for acknowledgements once the command exited
+        let mut ack_deadline = Instant::now() + ACK_TIMEOUT;
+        // set once the connection is gone for good, output is discarded from then on
+        let mut abandoned = false;
+        let mut terminating: Option<Terminating> = None;
+        // when the command times out, and when it is considered idle unless
+        // it writes output before then
+        let mut timeout_at: Option<Instant> = None;
+        let mut idle_at: Option<Instant> = None;
+        // when a local shutdown must be done by, and whether that passed
+        let mut drain_at: Option<Instant> = None;
+        let mut drain_expired = false;
 
-        rx.send("Hello");
-        loop {
+        let status = loop {
+            let retry_at = connection.retry_at();
+            let reading = status.is_none() && (abandoned || !spool.is_blocking());
+            let awaiting_ack = status.is_some() && connection.is_authenticated();
+            let kill_at = terminating.as_ref().and_then(Terminating::kill_at);
+            let killing = status.is_none() && kill_at.is_some();
+            let kill_at = kill_at.unwrap_or(ack_deadline);
+            // the watchdogs only ever start the termination, and the command
+            // is not blamed for the output it could not write while blocked
+            let watching = status.is_none() && terminating.is_none();
+            let timing_out = watching && timeout_at.is_some();
+            let timeout_deadline = timeout_at.unwrap_or(ack_deadline);
+            let idling = watching && reading && idle_at.is_some();
+            let idle_deadline = idle_at.unwrap_or(ack_deadline);
+            let draining = drain_at.is_some() && !drain_expired;
+            let drain_deadline = drain_at.unwrap_or(ack_deadline);
             tokio::select! {
-                Ok(Some(packet)) = websocket.next() => {
-                    // info!("closing websocket");
-                    // websocket.close().await;
-                    // heartbeat.close();
-                    // tx.close();
+                packet = connection.next(), if connection.is_connected() => {
+                    let packet = match packet {
+                        Some(packet) => packet,
+                        None if connection.is_reconnecting() => continue,
+                        None => match status {
+                            Some(status) => break status,
+                            None if child.is_some() => {
+                                warn!("websocket closed, the command output is no longer sent");
+                                abandoned = true;
+                                continue;
+                            }
+                            None => {
+                                return Err(ApplicationError::transport(
+                                    "websocket closed before the command was launched",
+                                ))
+                            }
+                        },
+                    };
+                    debug!("packet received: {}", packet);
                     match packet {
-                        Packet::Ping => {
-                            // send ping back
-                            debug!("Sending ping back");
-                            websocket.ping().await;
-                        }
-                        Packet::Pong => {}
+                        Packet::Ping => connection.pong().await,
+                        // the heartbeat and the resumption are handled by the connection
+                        Packet::Pong(_) => {}
                         Packet::AuthenticationSuccessful => {
                             debug!("successfully authenticated");
-                            // launch spawn command
+                            connection.authenticated().await;
+                            if let Some(command) = command.take() {
+                                info!("launching command: {:?}", command);
+                                let mut spawned = command.spawn(sender.clone())?;
+                                if !self.environment.borrow().remote_input() {
+                                    // nothing will ever be written, let the command see the end
+                                    // of its input rather than wait for it
+                                    spawned.close_stdin();
+                                }
+                                debug!("command launched (pid = {})", spawned.pid());
+                                timeout_at = max_duration.map(|timeout| Instant::now() + timeout);
+                                idle_at = max_idle.map(|timeout| Instant::now() + timeout);
+                                child = Some(spawned);
+                                connection.send(&Packet::CommandLaunched).await;
+                            }
+                            spool.rewind();
+                            self.flush(connection, &mut spool).await?;
+                            ack_deadline = Instant::now() + ACK_TIMEOUT;
+                            if let (Some(status), Some(summary)) = (status, &summary) {
+                                if self.report(connection, &mut spool, summary).await? {
+                                    break status;
+                                }
+                            }
+                        }
+                        Packet::AuthenticationFailed(message) => {
+                            if !connection.authentication_failed().await {
+                                connection.close().await?;
+                                return Err(ApplicationError::transport(format!(
+                                    "authentication failed: {}",
+                                    message
+                                )));
+                            }
                         }
-                        Packet::AuthenticationFailed(_) => {
-                            // exit program
+                        Packet::Ack(acknowledged) => {
+                            let count = spool.acknowledge(acknowledged)?;
+                            debug!("{} packets acknowledged up to {}", count, acknowledged);
+                            if let (Some(status), Some(summary)) = (status, &summary) {
+                                ack_deadline = Instant::now() + ACK_TIMEOUT;
+                                if self.report(connection, &mut spool, summary).await? {
+                                    break status;
+                                }
+                            }
+                        }
+                        Packet::Resize { columns, rows } => {
+                            if let Some(child) = &child {
+                                let size = WindowSize::new(columns, rows);
+                                match child.resize(size) {
+                                    Ok(()) => debug!("terminal resized to {}", size),
+                                    Err(error) => warn!("unable to resize: {}", error),
+                                }
+                            }
+                        }
+                        Packet::CommandInput { message, encoding, eof } => {
+                            match &mut child {
+                                Some(_) if drain_at.is_some() => {
+                                    debug!("ignoring input while shutting down");
+                                }
+                                Some(child) if self.environment.borrow().remote_input() => {
+                                    Self::input(child, message, encoding, eof);
+                                }
+                                Some(_) => warn!("ignoring input, remote input is disabled"),
+                                None => {}
+                            }
                         }
                         Packet::CommandTerminate => {
-                            // terminate the launched command
+                            if let Some(child) = &child {
+                                self.terminate(child, TerminationReason::Server, &mut terminating)?;
+                            }
                         }
-                        _ => debug!("ignoring unknown packet")
+                        _ => debug!("ignoring unknown packet"),
+                    }
+                }
+                _ = sleep_until(retry_at), if connection.is_reconnecting() => {
+                    if let Err(error) = connection.reconnect().await {
+                        error!("{}", error);
+                        match status {
+                            Some(status) => break status,
+                            None if child.is_some() => {
+                                warn!("the command output is no longer sent");
+                                abandoned = true;
+                            }
+                            None => return Err(error),
+                        }
+                    }
+                }
+                Some(()) = shutdown.recv() => match (&mut child, status) {
+                    (_, Some(status)) => {
+                        warn!("interrupted while waiting for the output to be acknowledged");
+                        if let Some(summary) = &summary {
+                            self.terminated(connection, summary).await?;
+                        }
+                        break status;
+                    }
+                    (Some(child), None) => {
+                        if drain_at.is_none() {
+                            let timeout = self.environment.borrow().shutdown().drain_timeout();
+                            info!("shutting down within {}s", timeout.as_secs());
+                            drain_at = Some(Instant::now() + timeout);
+                            child.close_stdin();
+                        }
+                        self.terminate(child, TerminationReason::LocalSignal, &mut terminating)?;
+                    }
+                    (None, None) => {
+                        connection.close().await?;
+                        return Err(ApplicationError::command(
+                            "interrupted before the command was launched",
+                        ));
+                    }
+                },
+                _ = sleep_until(kill_at), if killing => {
+                    if let (Some(child), Some(terminating)) = (&child, &mut terminating) {
+                        self.escalate(child, terminating)?;
+                    }
+                }
+                _ = sleep_until(timeout_deadline), if timing_out => {
+                    if let Some(child) = &child {
+                        let timeout = max_duration.unwrap_or_default().as_secs();
+                        warn!("command timed out after {}s", timeout);
+                        self.terminate(child, TerminationReason::Timeout, &mut terminating)?;
+                    }
+                }
+                _ = sleep_until(idle_deadline), if idling => {
+                    if let Some(child) = &child {
+                        let timeout = max_idle.unwrap_or_default().as_secs();
+                        warn!("command wrote no output for {}s", timeout);
+                        self.terminate(child, TerminationReason::Idle, &mut terminating)?;
+                    }
+                }
+                _ = sleep_until(drain_deadline), if draining => {
+                    warn!("the command was not drained in time, shutting down regardless");
+                    drain_expired = true;
+                    if let (Some(status), Some(summary)) = (status, &summary) {
+                        self.terminated(connection, summary).await?;
+                        break status;
+                    }
+                    if let (Some(child), Some(terminating)) = (&child, &mut terminating) {
+                        self.escalate(child, terminating)?;
+                    }
+                }
+                _ = sleep_until(ack_deadline), if awaiting_ack => {
+                    warn!(
+                        "{} packets were not acknowledged within {}s",
+                        spool.len(),
+                        ACK_TIMEOUT.as_secs()
+                    );
+                    if let (Some(status), Some(summary)) = (status, &summary) {
+                        self.terminated(connection, summary).await?;
+                        break status;
                     }
-                    debug!("packet received: {}", packet);
                 }
-                else => {
-                    info!("shutting down system");
-                    break;
+                Some(event) = receiver.recv(), if reading => {
+                    if event.is_output() {
+                        idle_at = max_idle.map(|timeout| Instant::now() + timeout);
+                    }
+                    match event {
+                        CommandEvent::Stdout { .. }
+                        | CommandEvent::Stderr { .. }
+                        | CommandEvent::Terminal { .. }
+                            if abandoned => {}
+                        CommandEvent::Stdout { data, timestamp } => {
+                            sequence += 1;
+                            let packet = Self::output_packet(sequence, "stdout", data, timestamp);
+                            self.output(connection, &mut spool, sequence, packet).await?;
+                        }
+                        CommandEvent::Stderr { data, timestamp } => {
+                            sequence += 1;
+                            let packet = Self::output_packet(sequence, "stderr", data, timestamp);
+                            self.output(connection, &mut spool, sequence, packet).await?;
+                        }
+                        CommandEvent::Terminal { data, timestamp } => {
+                            sequence += 1;
+                            let packet = Self::output_packet(sequence, "tty", data, timestamp);
+                            self.output(connection, &mut spool, sequence, packet).await?;
+                        }
+                        CommandEvent::Error { message, timestamp } => {
+                            warn!(
+                                "unable to read command output at {}: {}",
+                                timestamp.time(),
+                                message
+                            );
+                        }
+                        CommandEvent::Exited { code, signal, lingering, timestamp } => {
+                            let exited = ExitStatus::new(code, signal);
+                            info!(
+                                "command exited with {} after {:.3}s",
+                                exited,
+                                timestamp.offset().as_secs_f64()
+                            );
+                            for process in &lingering {
+                                warn!("stopped lingering process {} ({})", process.pid, process.name);
+                            }
+                            let packet = Packet::CommandTerminated {
+                                code: exited.code(),
+                                signal: exited.signal(),
+                                termination: terminating.as_ref().map(Terminating::report),
+                                lingering,
+                            };
+                            if abandoned || self.report(connection, &mut spool, &packet).await? {
+                                break exited;
+                            }
+                            if drain_expired {
+                                self.terminated(connection, &packet).await?;
+                                break exited;
+                            }
+                            if !connection.is_connected() && !connection.is_reconnecting() {
+                                break exited;
+                            }
+                            info!("waiting for the server to acknowledge the output");
+                            ack_deadline = Instant::now() + ACK_TIMEOUT;
+                            status = Some(exited);
+                            summary = Some(packet);
+                        }
+                    }
                 }
             }
+        };
+
+        if spool.dropped() > 0 {
+            warn!(
+                "{} lines of output were dropped because the spool was full",
+                spool.dropped()
+            );
         }
+        spool.remove()?;
         info!("shut down system");
+        Ok(status)
+    }
+
+    /// asks the command to stop with the configured signal, or kills it
+    /// straight away when it was already asked to
+    fn terminate(
+        &self,
+        child: &impl Child,
+        reason: TerminationReason,
+        terminating: &mut Option<Terminating>,
+    ) -> Result<(), ApplicationError> {
+        if let Some(terminating) = terminating {
+            return self.escalate(child, terminating);
+        }
+        let settings = self.environment.borrow().terminate().clone();
+        info!(
+            "terminating command (pid = {}) with {}",
+            child.pid(),
+            settings.signal()
+        );
+        child.signal(settings.signal())?;
+        *terminating = Some(Terminating {
+            reason,
+            signal: settings.signal(),
+            kill_at: Instant::now() + settings.grace_period(),
+            escalated: false,
+        });
         Ok(())
     }
+
+    /// kills the command when it did not exit after being asked to
+    fn escalate(
+        &self,
+        child: &impl Child,
+        terminating: &mut Terminating,
+    ) -> Result<(), ApplicationError> {
+        if terminating.signal == Signal::KILL {
+            return Ok(());
+        }
+        warn!(
+            "command (pid = {}) is still running, killing it",
+            child.pid()
+        );
+        child.kill()?;
+        terminating.signal = Signal::KILL;
+        terminating.escalated = true;
+        Ok(())
+    }
+
+    /// writes input from the server to the command
+    fn input(child: &mut impl Child, message: String, encoding: Encoding, eof: bool) {
+        let data = match encoding {
+            Encoding::Utf8 => message.into_bytes(),
+            Encoding::Base64 => match base64::decode(&message) {
+                Ok(data) => data,
+                Err(error) => {
+                    warn!("ignoring input that is not valid base64: {}", error);
+                    return;
+                }
+            },
+        };
+        if !data.is_empty() {
+            if let Err(error) = child.write(&data) {
+                warn!("unable to forward input: {}", error);
+            }
+        }
+        if eof {
+            debug!("closing the standard input of the command");
+            child.close_stdin();
+        }
+    }
+
+    /// builds the packet for output, base64 encoding it unless it is valid UTF-8
+    fn output_packet(sequence: u64, stream: &str, data: Vec<u8>, timestamp: Timestamp) -> Packet {
+        let (message, encoding) = match String::from_utf8(data) {
+            Ok(message) => (message, Encoding::Utf8),
+            Err(error) => (base64::encode(error.into_bytes()), Encoding::Base64),
+        };
+        Packet::CommandOutput {
+            sequence,
+            stream: stream.to_string(),
+            message,
+            encoding,
+            timestamp: timestamp.time(),
+            offset_us: timestamp.offset().as_micros() as u64,
+        }
+    }
+
+    /// sends output straight away unless earlier output is still waiting to
+    /// be sent, in which case it is queued behind it. Either way it is kept in
+    /// the spool until the server acknowledges it.
+    async fn output(
+        &self,
+        connection: &mut impl Link,
+        spool: &mut Spool,
+        sequence: u64,
+        packet: Packet,
+    ) -> Result<(), ApplicationError> {
+        let sent = !spool.has_unsent() && connection.send(&packet).await;
+        spool.push(sequence, &packet, sent)
+    }
+
+    /// sends the spooled output in order, stopping when the connection drops
+    async fn flush(
+        &self,
+        connection: &mut impl Link,
+        spool: &mut Spool,
+    ) -> Result<(), ApplicationError> {
+        if !spool.has_unsent() || !connection.is_authenticated() {
+            return Ok(());
+        }
+        info!("sending {} unacknowledged packets", spool.len());
+        while let Some(packet) = spool.next_unsent()? {
+            if !connection.send(&packet).await {
+                info!("sending interrupted, {} packets still spooled", spool.len());
+                return Ok(());
+            }
+            spool.mark_sent();
+        }
+        Ok(())
+    }
+
+    /// reports how the command terminated and closes the connection once all
+    /// of its output was acknowledged, returning whether it was reported
+    async fn report(
+        &self,
+        connection: &mut impl Link,
+        spool: &mut Spool,
+        summary: &Packet,
+    ) -> Result<bool, ApplicationError> {
+        self.flush(connection, spool).await?;
+        if !spool.is_empty() || !connection.is_authenticated() {
+            return Ok(false);
+        }
+        self.terminated(connection, summary).await
+    }
+
+    /// sends how the command terminated and closes the connection, returning
+    /// whether it was sent
+    async fn terminated(
+        &self,
+        connection: &mut impl Link,
+        summary: &Packet,
+    ) -> Result<bool, ApplicationError> {
+        if !connection.send(summary).await {
+            return Ok(false);
+        }
+        connection.close().await?;
+        Ok(true)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::collections::VecDeque;
+    use std::time::Instant as Launched;
+
+    use tokio::sync::mpsc::unbounded_channel;
+
+    use super::*;
+
+    /// A server which acknowledges the output it receives, if asked to
+    #[derive(Default)]
+    struct FakeLink {
+        incoming: VecDeque<Packet>,
+        sent: Vec<Packet>,
+        acknowledging: bool,
+        authenticated: bool,
+        closed: bool,
+    }
+
+    impl FakeLink {
+        /// how the command was stopped, as reported to the server
+        fn termination(&self) -> Option<Termination> {
+            self.sent.iter().find_map(|packet| match packet {
+                Packet::CommandTerminated { termination, .. } => termination.clone(),
+                _ => None,
+            })
+        }
+    }
+
+    impl Link for FakeLink {
+        fn is_connected(&self) -> bool {
+            !self.closed
+        }
+
+        fn is_authenticated(&self) -> bool {
+            self.authenticated && !self.closed
+        }
+
+        fn is_reconnecting(&self) -> bool {
+            false
+        }
+
+        fn retry_at(&self) -> Instant {
+            Instant::now()
+        }
+
+        async fn next(&mut self) -> Option<Packet> {
+            match self.incoming.pop_front() {
+                Some(packet) => Some(packet),
+                None => std::future::pending().await,
+            }
+        }
+
+        async fn authenticated(&mut self) {
+            self.authenticated = true;
+        }
+
+        async fn authentication_failed(&mut self) -> bool {
+            false
+        }
+
+        async fn send(&mut self, packet: &Packet) -> bool {
+            if self.closed {
+                return false;
+            }
+            if let (true, Packet::CommandOutput { sequence, .. }) = (self.acknowledging, packet) {
+                self.incoming.push_back(Packet::Ack(*sequence));
+            }
+            self.sent.push(packet.clone());
+            true
+        }
+
+        async fn pong(&mut self) {}
+
+        async fn reconnect(&mut self) -> Result<(), ApplicationError> {
+            Ok(())
+        }
+
+        async fn close(&mut self) -> Result<(), ApplicationError> {
+            self.closed = true;
+            Ok(())
+        }
+    }
+
+    /// A command which writes the given lines, each after a delay, and exits
+    /// when it receives one of the given signals or SIGKILL
+    #[derive(Debug, Default)]
+    struct FakeCommand {
+        timeout: Option<Duration>,
+        idle_timeout: Option<Duration>,
+        output: Vec<(Duration, &'static str)>,
+        exits_on: Vec<Signal>,
+    }
+
+    struct FakeChild {
+        bus: Sender<CommandEvent>,
+        exits_on: Vec<Signal>,
+        launched: Launched,
+    }
+
+    impl Launch for FakeCommand {
+        type Child = FakeChild;
+
+        fn max_duration(&self) -> Option<Duration> {
+            self.timeout
+        }
+
+        fn max_idle(&self) -> Option<Duration> {
+            self.idle_timeout
+        }
+
+        fn spawn(self, bus: Sender<CommandEvent>) -> Result<FakeChild, ApplicationError> {
+            let launched = Launched::now();
+            let output = bus.clone();
+            tokio::spawn(async move {
+                for (delay, line) in self.output {
+                    tokio::time::sleep(delay).await;
+                    let data = line.as_bytes().to_vec();
+                    let timestamp = Timestamp::since(launched);
+                    let _ = output.send(CommandEvent::Stdout { data, timestamp }).await;
+                }
+            });
+            Ok(FakeChild {
+                bus,
+                exits_on: self.exits_on,
+                launched,
+            })
+        }
+    }
+
+    impl Child for FakeChild {
+        fn pid(&self) -> u32 {
+            4242
+        }
+
+        fn signal(&self, signal: Signal) -> Result<(), ApplicationError> {
+            if signal == Signal::KILL || self.exits_on.contains(&signal) {
+                let _ = self.bus.try_send(CommandEvent::Exited {
+                    code: None,
+                    signal: Some(signal.number()),
+                    lingering: vec![],
+                    timestamp: Timestamp::since(self.launched),
+                });
+            }
+            Ok(())
+        }
+
+        fn kill(&self) -> Result<(), ApplicationError> {
+            self.signal(Signal::KILL)
+        }
+
+        fn write(&self, _data: &[u8]) -> Result<(), ApplicationError> {
+            Ok(())
+        }
+
+        fn close_stdin(&mut self) {}
+
+        fn resize(&self, _size: WindowSize) -> Result<(), ApplicationError> {
+            Ok(())
+        }
+    }
+
+    fn manager() -> Manager {
+        let environment = serde_yaml::from_str(
+            r#"
+                host: localhost:8080
+                https: false
+                token: a super long token
+                logging: DEBUG
+                terminate:
+                    grace_period: 5
+                shutdown:
+                    drain_timeout: 8
+            "#,
+        )
+        .unwrap();
+        Manager::new(watch::channel(environment).1)
+    }
+
+    /// Runs the command until it is reported, returning how long that took,
+    /// with a local shutdown after the given delay if any
+    async fn run(
+        name: &str,
+        link: &mut FakeLink,
+        command: FakeCommand,
+        shutdown_after: Option<Duration>,
+    ) -> (ExitStatus, Duration) {
+        let manager = manager();
+        let path = std::env::temp_dir()
+            .join(format!("tracer-{}", std::process::id()))
+            .join(format!("manager-{}.jsonl", name));
+        let spool = Spool::open(&path, manager.environment.borrow().spool()).unwrap();
+        let (shutdown, mut shutdown_receiver) = unbounded_channel();
+        if let Some(delay) = shutdown_after {
+            tokio::spawn(async move {
+                tokio::time::sleep(delay).await;
+                shutdown.send(()).unwrap();
+            });
+        }
+        link.incoming.push_front(Packet::AuthenticationSuccessful);
+
+        let start = Instant::now();
+        let status = manager
+            .run(link, spool, command, &mut shutdown_receiver)
+            .await
+            .unwrap();
+        (status, start.elapsed())
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_terminate_escalates_to_kill() {
+        let mut link = FakeLink {
+            incoming: VecDeque::from([Packet::CommandTerminate]),
+            ..Default::default()
+        };
+
+        let (status, elapsed) = run("kill", &mut link, FakeCommand::default(), None).await;
+
+        assert_eq!(status.signal(), Some(libc::SIGKILL));
+        assert_eq!(elapsed, Duration::from_secs(5));
+        assert_eq!(
+            link.termination(),
+            Some(Termination {
+                reason: TerminationReason::Server,
+                signal: "SIGKILL".to_string(),
+                escalated: true,
+            })
+        );
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_timeout() {
+        let mut link = FakeLink::default();
+        let command = FakeCommand {
+            timeout: Some(Duration::from_secs(3)),
+            exits_on: vec![Signal::TERM],
+            ..Default::default()
+        };
+
+        let (status, elapsed) = run("timeout", &mut link, command, None).await;
+
+        assert_eq!(status.signal(), Some(libc::SIGTERM));
+        assert_eq!(elapsed, Duration::from_secs(3));
+        assert_eq!(
+            link.termination().map(|termination| termination.reason),
+            Some(TerminationReason::Timeout)
+        );
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_idle_timeout() {
+        let mut link = FakeLink {
+            acknowledging: true,
+            ..Default::default()
+        };
+        let command = FakeCommand {
+            idle_timeout: Some(Duration::from_secs(3)),
+            output: vec![(Duration::from_secs(2), "still there\n")],
+            exits_on: vec![Signal::TERM],
+            ..Default::default()
+        };
+
+        let (_, elapsed) = run("idle", &mut link, command, None).await;
+
+        // the output restarted the idle timeout
+        assert_eq!(elapsed, Duration::from_secs(5));
+        assert_eq!(
+            link.termination().map(|termination| termination.reason),
+            Some(TerminationReason::Idle)
+        );
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_drain_timeout() {
+        // the server never acknowledges the output
+        let mut link = FakeLink::default();
+        let command = FakeCommand {
+            output: vec![(Duration::from_millis(500), "last words\n")],
+            exits_on: vec![Signal::TERM],
+            ..Default::default()
+        };
+
+        let (status, elapsed) =
+            run("drain", &mut link, command, Some(Duration::from_secs(1))).await;
+
+        assert_eq!(status.signal(), Some(libc::SIGTERM));
+        assert_eq!(elapsed, Duration::from_secs(9));
+        assert_eq!(
+            link.termination().map(|termination| termination.reason),
+            Some(TerminationReason::LocalSignal)
+        );
+        assert!(link.closed);
+    }
 }
@@ -1,7 +1,4 @@
-use std::str::FromStr;
-
-use reqwest::{Client, header, Response};
-use serde_json::json;
+use reqwest::{header, Client, Response, StatusCode};
 
 use crate::common::{Command, CommandCreate, Session};
 use crate::error::ApplicationError;
@@ -22,12 +19,9 @@ impl RequestExt for Response {
 }
 
 impl ApiClient {
-
     pub fn new(url: &str, token: &str) -> Result<Self, ApplicationError> {
         let default_headers = Self::default_headers(token)?;
-        let client = Client::builder()
-            .default_headers(default_headers)
-            .build()?;
+        let client = Client::builder().default_headers(default_headers).build()?;
 
         Ok(Self {
             url: url.into(),
@@ -51,27 +45,30 @@ impl ApiClient {
         Ok(headers)
     }
 
+    /// The commands registered, or `None` when the server rejected the token
     pub async fn commands(&self) -> Result<Option<Vec<Command>>, ApplicationError> {
         let url = format!("{}/commands", self.url);
         let response = self.client.get(url).send().await?;
-        if response.is_successful() {
-            let commands = response.json().await?;
-            return Ok(Some(commands));
+        match response.status() {
+            status if status.is_success() => Ok(Some(response.json().await?)),
+            StatusCode::UNAUTHORIZED | StatusCode::FORBIDDEN => Ok(None),
+            status => Err(ApplicationError::transport(format!(
+                "the server answered {}",
+                status
+            ))),
         }
-        Ok(None)
     }
 
     pub async fn create_command(
         &self,
         name: impl AsRef<str>,
+        tags: &[String],
     ) -> Result<Option<Command>, ApplicationError> {
         let url = format!("{}/commands", self.url);
         let response = self
             .client
             .post(url)
-            .json(&json!({
-                "name": name.as_ref()
-            }))
+            .json(&CommandCreate::new(name.as_ref()).tags(tags))
             .send()
             .await?;
         if response.is_successful() {
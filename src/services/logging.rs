@@ -1,28 +1,129 @@
+use std::fs::{File, OpenOptions};
+use std::io::{self, Write};
+use std::path::{Path, PathBuf};
 use std::str::FromStr;
+use std::sync::{Arc, Mutex};
 
+use crate::config::configuration::Environment;
 use crate::error::ApplicationError;
 use crate::services::Service;
 
 pub struct Logging {
     level: String,
+    file: Option<PathBuf>,
+    output: Option<LogFile>,
 }
 
 impl Logging {
     pub fn new(level: &str) -> Self {
         Self {
             level: level.into(),
+            file: None,
+            output: None,
         }
     }
+
+    /// Writes the logs to the given file rather than to stderr
+    pub fn file(mut self, path: Option<&Path>) -> Self {
+        self.file = path.map(Path::to_path_buf);
+        self
+    }
 }
 
 impl Service for Logging {
-    fn bootstrap(&self) -> Result<(), ApplicationError> {
+    fn name(&self) -> &str {
+        "logging"
+    }
+
+    fn start(&mut self) -> Result<(), ApplicationError> {
         let level = self.level.as_str();
         let level = log::LevelFilter::from_str(level)?;
         let mut builder = env_logger::Builder::new();
-        builder.filter_level(level);
+        // filtering is left to the maximum level so that it can be reloaded
+        builder.filter_level(log::LevelFilter::Trace);
+        if let Some(path) = &self.file {
+            let output = LogFile::open(path)?;
+            builder.target(env_logger::Target::Pipe(Box::new(output.clone())));
+            builder.write_style(env_logger::WriteStyle::Never);
+            // env_logger 0.9.0 only writes to a pipe in test mode
+            builder.is_test(true);
+            self.output = Some(output);
+        }
         builder.init();
+        log::set_max_level(level);
         debug!("Initialised logging level to {}", level);
         Ok(())
     }
+
+    fn stop(&mut self) -> Result<(), ApplicationError> {
+        log::logger().flush();
+        Ok(())
+    }
+
+    fn reload(&mut self, environment: &Environment) -> Result<(), ApplicationError> {
+        let level = log::LevelFilter::from_str(environment.logging())?;
+        if let Some(output) = &self.output {
+            // a rotated log file is only let go of once reopened
+            output.reopen()?;
+            debug!("reopened the log file {}", output.path.display());
+        }
+        if level != log::max_level() {
+            log::set_max_level(level);
+            info!("logging level changed to {}", level);
+        }
+        self.level = environment.logging().to_string();
+        Ok(())
+    }
+}
+
+/// A log file shared with the logger, which can be reopened once rotated
+#[derive(Clone)]
+struct LogFile {
+    path: PathBuf,
+    file: Arc<Mutex<File>>,
+}
+
+impl LogFile {
+    fn open(path: &Path) -> Result<Self, ApplicationError> {
+        Ok(Self {
+            path: path.to_path_buf(),
+            file: Arc::new(Mutex::new(Self::append(path)?)),
+        })
+    }
+
+    fn reopen(&self) -> Result<(), ApplicationError> {
+        let file = Self::append(&self.path)?;
+        *self.file.lock().unwrap_or_else(|error| error.into_inner()) = file;
+        Ok(())
+    }
+
+    fn append(path: &Path) -> Result<File, ApplicationError> {
+        OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(path)
+            .map_err(|error| {
+                ApplicationError::io(format!(
+                    "unable to open the log file {}: {}",
+                    path.display(),
+                    error
+                ))
+            })
+    }
+}
+
+impl Write for LogFile {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.file
+            .lock()
+            .unwrap_or_else(|error| error.into_inner())
+            .write(buf)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.file
+            .lock()
+            .unwrap_or_else(|error| error.into_inner())
+            .flush()
+    }
 }
@@ -1,5 +1,19 @@
+use crate::config::configuration::Environment;
 use crate::error::ApplicationError;
 
+/// A part of the application with a lifecycle: started before the command is
+/// launched, reloaded when the configuration changes and stopped once tracer
+/// is done with the command.
 pub trait Service {
-    fn bootstrap(&self) -> Result<(), ApplicationError>;
+    fn name(&self) -> &str;
+
+    fn start(&mut self) -> Result<(), ApplicationError>;
+
+    fn stop(&mut self) -> Result<(), ApplicationError> {
+        Ok(())
+    }
+
+    fn reload(&mut self, _environment: &Environment) -> Result<(), ApplicationError> {
+        Ok(())
+    }
 }
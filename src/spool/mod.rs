@@ -0,0 +1,3 @@
+pub use store::Spool;
+
+mod store;
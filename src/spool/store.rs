@@ -0,0 +1,508 @@
+use std::collections::VecDeque;
+use std::fs::{self, File, OpenOptions};
+use std::io::{Read, Seek, SeekFrom, Write};
+use std::path::{Path, PathBuf};
+
+use crate::config::configuration::{self, FullPolicy};
+use crate::error::ApplicationError;
+use crate::ws::Packet;
+
+/// An entry of the spool, in the order of the sequence numbers
+#[derive(Debug, Clone, Copy)]
+enum Entry {
+    /// a packet, located by its offset and size in the file
+    Stored {
+        sequence: u64,
+        offset: u64,
+        size: u64,
+    },
+    /// the sequence numbers of the packets dropped because the spool was full,
+    /// which the server is told about so that it acknowledges past them
+    Skipped { from: u64, to: u64 },
+}
+
+impl Entry {
+    /// the sequence numbers the entry stands for
+    fn range(&self) -> (u64, u64) {
+        match *self {
+            Entry::Stored { sequence, .. } => (sequence, sequence),
+            Entry::Skipped { from, to } => (from, to),
+        }
+    }
+}
+
+/// An append-only file of output packets waiting to be acknowledged by the
+/// server, one JSON document per line. Entries are kept until the server
+/// acknowledges their sequence number, so that they can be retransmitted
+/// after a reconnection, and the file is truncated whenever it has been
+/// drained. The sequence numbers of dropped packets are kept in their place,
+/// so that the server is told they will never be sent.
+pub struct Spool {
+    path: PathBuf,
+    file: File,
+    /// offset of the oldest entry still waiting to be acknowledged
+    head: u64,
+    /// offset of the end of the file
+    tail: u64,
+    /// the entries waiting to be acknowledged, oldest first
+    entries: VecDeque<Entry>,
+    /// the number of entries, from the oldest, sent on the current connection
+    sent: usize,
+    max_size: u64,
+    policy: FullPolicy,
+    dropped: u64,
+}
+
+impl Spool {
+    /// Creates an empty spool at the given path, replacing any existing file
+    pub fn open(path: &Path, settings: &configuration::Spool) -> Result<Self, ApplicationError> {
+        if let Some(parent) = path.parent() {
+            fs::create_dir_all(parent)?;
+        }
+        let file = OpenOptions::new()
+            .read(true)
+            .write(true)
+            .create(true)
+            .truncate(true)
+            .open(path)
+            .map_err(|error| {
+                ApplicationError::io(format!(
+                    "unable to open the spool {}: {}",
+                    path.display(),
+                    error
+                ))
+            })?;
+        debug!("spooling output to {}", path.display());
+        Ok(Self {
+            path: path.to_path_buf(),
+            file,
+            head: 0,
+            tail: 0,
+            entries: VecDeque::new(),
+            sent: 0,
+            max_size: settings.max_size(),
+            policy: settings.when_full(),
+            dropped: 0,
+        })
+    }
+
+    /// The location of the spool of a session inside the user's data directory,
+    /// i.e. `$XDG_DATA_HOME/tracer/spool/<session>.jsonl`
+    #[cfg(not(windows))]
+    pub fn path_for(session_id: &str) -> Result<PathBuf, ApplicationError> {
+        let directories = xdg::BaseDirectories::with_prefix("tracer").map_err(|error| {
+            ApplicationError::io(format!("unable to find the data directory: {}", error))
+        })?;
+        Ok(directories
+            .get_data_home()
+            .join("spool")
+            .join(format!("{}.jsonl", session_id)))
+    }
+
+    #[cfg(windows)]
+    pub fn path_for(session_id: &str) -> Result<PathBuf, ApplicationError> {
+        dirs::data_dir()
+            .map(|path| {
+                path.join("tracer\\spool")
+                    .join(format!("{}.jsonl", session_id))
+            })
+            .ok_or_else(|| ApplicationError::io("unable to find the data directory"))
+    }
+
+    /// whether every entry has been acknowledged
+    pub fn is_empty(&self) -> bool {
+        self.entries.is_empty()
+    }
+
+    pub fn len(&self) -> usize {
+        self.entries.len()
+    }
+
+    /// whether some entries have not been sent on the current connection
+    pub fn has_unsent(&self) -> bool {
+        self.sent < self.entries.len()
+    }
+
+    /// the size in bytes of the entries waiting to be acknowledged
+    pub fn size(&self) -> u64 {
+        self.tail - self.head
+    }
+
+    pub fn is_full(&self) -> bool {
+        self.size() >= self.max_size
+    }
+
+    /// whether the command's output should not be read until there is room
+    pub fn is_blocking(&self) -> bool {
+        self.policy == FullPolicy::Block && self.is_full()
+    }
+
+    /// the number of packets discarded because the spool was full
+    pub fn dropped(&self) -> u64 {
+        self.dropped
+    }
+
+    /// Appends a packet, applying the policy when the size cap is reached.
+    /// Packets can only be marked as sent when nothing older is unsent, and a
+    /// packet sent but dropped is still skipped should it have been lost.
+    pub fn push(
+        &mut self,
+        sequence: u64,
+        packet: &Packet,
+        sent: bool,
+    ) -> Result<(), ApplicationError> {
+        debug_assert!(!sent || !self.has_unsent());
+        let mut line = serde_json::to_vec(packet)?;
+        line.push(b'\n');
+        let size = line.len() as u64;
+
+        if self.size() + size > self.max_size {
+            match self.policy {
+                FullPolicy::DropNewest => {
+                    self.dropped += 1;
+                    self.skip(sequence, sent);
+                    return Ok(());
+                }
+                FullPolicy::DropOldest => self.drop_oldest(size),
+                // the caller stops reading output while the spool is full
+                FullPolicy::Block => {}
+            }
+        }
+
+        self.file.seek(SeekFrom::Start(self.tail))?;
+        self.file.write_all(&line)?;
+        self.entries.push_back(Entry::Stored {
+            sequence,
+            offset: self.tail,
+            size,
+        });
+        self.tail += size;
+        if sent {
+            self.sent += 1;
+        }
+        self.compact()?;
+        Ok(())
+    }
+
+    /// The oldest entry not sent on the current connection
+    pub fn next_unsent(&mut self) -> Result<Option<Packet>, ApplicationError> {
+        let (offset, size) = match self.entries.get(self.sent) {
+            Some(Entry::Stored { offset, size, .. }) => (*offset, *size),
+            Some(Entry::Skipped { from, to }) => {
+                return Ok(Some(Packet::Skipped {
+                    from: *from,
+                    to: *to,
+                }))
+            }
+            None => return Ok(None),
+        };
+        let mut line = vec![0; size as usize];
+        self.file.seek(SeekFrom::Start(offset))?;
+        self.file.read_exact(&mut line)?;
+        let packet = serde_json::from_slice(&line)?;
+        Ok(Some(packet))
+    }
+
+    /// Marks the entry returned by `next_unsent` as sent
+    pub fn mark_sent(&mut self) {
+        self.sent = (self.sent + 1).min(self.entries.len());
+    }
+
+    /// Marks every entry as unsent so that they are retransmitted, i.e. after
+    /// a new connection was established
+    pub fn rewind(&mut self) {
+        self.sent = 0;
+    }
+
+    /// Removes the entries up to and including the given sequence number once
+    /// the server acknowledged them, returning how many were removed
+    pub fn acknowledge(&mut self, sequence: u64) -> Result<usize, ApplicationError> {
+        let mut count = 0;
+        while let Some(entry) = self.entries.front_mut() {
+            match *entry {
+                Entry::Skipped { from, to } if from <= sequence && sequence < to => {
+                    // what the server received before it was dropped
+                    *entry = Entry::Skipped {
+                        from: sequence + 1,
+                        to,
+                    };
+                    break;
+                }
+                entry if entry.range().1 <= sequence => {
+                    self.discard_front();
+                    count += 1;
+                }
+                _ => break,
+            }
+        }
+        if self.entries.is_empty() {
+            self.truncate()?;
+        }
+        Ok(count)
+    }
+
+    /// Deletes the spool file, unless output is still waiting to be acknowledged
+    pub fn remove(self) -> Result<(), ApplicationError> {
+        if self.is_empty() {
+            fs::remove_file(&self.path)?;
+        } else {
+            warn!(
+                "{} entries of output were never acknowledged, they are kept in {}",
+                self.len(),
+                self.path.display()
+            );
+        }
+        Ok(())
+    }
+
+    fn discard_front(&mut self) -> Option<Entry> {
+        let entry = self.entries.pop_front()?;
+        if let Entry::Stored { offset, size, .. } = entry {
+            self.head = offset + size;
+        }
+        self.sent = self.sent.saturating_sub(1);
+        Some(entry)
+    }
+
+    /// Drops the oldest packets until there is room for the given size,
+    /// skipping their sequence numbers in their place
+    fn drop_oldest(&mut self, size: u64) {
+        let mut skipped: Option<(u64, u64)> = None;
+        let mut unsent = false;
+        while self.size() + size > self.max_size {
+            unsent |= self.sent == 0;
+            let entry = match self.discard_front() {
+                Some(entry) => entry,
+                None => break,
+            };
+            if let Entry::Stored { .. } = entry {
+                self.dropped += 1;
+            }
+            let (from, to) = entry.range();
+            skipped = Some((skipped.map_or(from, |(from, _)| from), to));
+        }
+        if let Some((from, to)) = skipped {
+            self.entries.push_front(Entry::Skipped { from, to });
+            // the range is told again unless all of it was sent
+            self.sent = if unsent { 0 } else { self.sent + 1 };
+        }
+    }
+
+    /// Skips the sequence number of the newest packet, along with those
+    /// skipped right before it
+    fn skip(&mut self, sequence: u64, sent: bool) {
+        if let Some(Entry::Skipped { to, .. }) = self.entries.back_mut() {
+            if *to + 1 == sequence {
+                *to = sequence;
+                if !sent {
+                    self.sent = self.sent.min(self.entries.len() - 1);
+                }
+                return;
+            }
+        }
+        self.entries.push_back(Entry::Skipped {
+            from: sequence,
+            to: sequence,
+        });
+        if sent {
+            self.sent += 1;
+        }
+    }
+
+    fn truncate(&mut self) -> Result<(), ApplicationError> {
+        self.file.set_len(0)?;
+        self.head = 0;
+        self.tail = 0;
+        Ok(())
+    }
+
+    /// Rewrites the file without the entries that were dropped or acknowledged
+    /// once they take up more room than the entries still waiting
+    fn compact(&mut self) -> Result<(), ApplicationError> {
+        if self.head < self.max_size || self.head < self.size() {
+            return Ok(());
+        }
+        let mut pending = vec![0; self.size() as usize];
+        self.file.seek(SeekFrom::Start(self.head))?;
+        self.file.read_exact(&mut pending)?;
+        self.file.set_len(0)?;
+        self.file.seek(SeekFrom::Start(0))?;
+        self.file.write_all(&pending)?;
+        for entry in self.entries.iter_mut() {
+            if let Entry::Stored { offset, .. } = entry {
+                *offset -= self.head;
+            }
+        }
+        self.head = 0;
+        self.tail = pending.len() as u64;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::env;
+
+    use super::*;
+    use crate::ws::Encoding;
+
+    fn settings(max_size: u64, when_full: &str) -> configuration::Spool {
+        let yaml = format!("max_size: {}\nwhen_full: {}", max_size, when_full);
+        serde_yaml::from_str(&yaml).unwrap()
+    }
+
+    fn spool(name: &str, max_size: u64, when_full: &str) -> Spool {
+        let path = env::temp_dir()
+            .join(format!("tracer-{}", std::process::id()))
+            .join(format!("{}.jsonl", name));
+        Spool::open(&path, &settings(max_size, when_full)).unwrap()
+    }
+
+    fn output(sequence: u64) -> Packet {
+        Packet::CommandOutput {
+            sequence,
+            stream: "stdout".to_string(),
+            message: sequence.to_string(),
+            encoding: Encoding::Utf8,
+            timestamp: chrono::Utc::now(),
+            offset_us: sequence,
+        }
+    }
+
+    fn sequence(packet: Option<Packet>) -> u64 {
+        match packet {
+            Some(Packet::CommandOutput { sequence, .. }) => sequence,
+            packet => panic!("unexpected packet {:?}", packet),
+        }
+    }
+
+    fn skipped(packet: Option<Packet>) -> (u64, u64) {
+        match packet {
+            Some(Packet::Skipped { from, to }) => (from, to),
+            packet => panic!("unexpected packet {:?}", packet),
+        }
+    }
+
+    fn entry_size() -> u64 {
+        serde_json::to_vec(&output(1)).unwrap().len() as u64 + 1
+    }
+
+    #[test]
+    fn test_send_in_order() {
+        let mut spool = spool("order", 1024, "drop_oldest");
+        spool.push(1, &output(1), true).unwrap();
+        spool.push(2, &output(2), false).unwrap();
+        spool.push(3, &output(3), false).unwrap();
+        assert_eq!(spool.len(), 3);
+
+        assert_eq!(sequence(spool.next_unsent().unwrap()), 2);
+        spool.mark_sent();
+        assert_eq!(sequence(spool.next_unsent().unwrap()), 3);
+        spool.mark_sent();
+        assert!(!spool.has_unsent());
+        assert!(spool.next_unsent().unwrap().is_none());
+
+        assert_eq!(spool.acknowledge(2).unwrap(), 2);
+        assert_eq!(spool.len(), 1);
+        assert_eq!(spool.acknowledge(3).unwrap(), 1);
+
+        assert!(spool.is_empty());
+        assert_eq!(fs::metadata(&spool.path).unwrap().len(), 0);
+        spool.remove().unwrap();
+    }
+
+    #[test]
+    fn test_retransmit_unacknowledged() {
+        let mut spool = spool("retransmit", 1024, "drop_oldest");
+        for number in 1..=4 {
+            spool.push(number, &output(number), true).unwrap();
+        }
+        spool.acknowledge(2).unwrap();
+        assert!(!spool.has_unsent());
+
+        spool.rewind();
+        assert_eq!(sequence(spool.next_unsent().unwrap()), 3);
+        spool.mark_sent();
+        assert_eq!(sequence(spool.next_unsent().unwrap()), 4);
+    }
+
+    #[test]
+    fn test_drop_oldest() {
+        let mut spool = spool("oldest", entry_size() * 2, "drop_oldest");
+        for number in 1..=3 {
+            spool.push(number, &output(number), false).unwrap();
+        }
+        assert_eq!(spool.len(), 3);
+        assert_eq!(spool.dropped(), 1);
+        assert_eq!(skipped(spool.next_unsent().unwrap()), (1, 1));
+        spool.mark_sent();
+        assert_eq!(sequence(spool.next_unsent().unwrap()), 2);
+    }
+
+    #[test]
+    fn test_drop_newest() {
+        let mut spool = spool("newest", entry_size() * 2, "drop_newest");
+        for number in 1..=3 {
+            spool.push(number, &output(number), false).unwrap();
+        }
+        assert_eq!(spool.len(), 3);
+        assert_eq!(spool.dropped(), 1);
+        assert!(spool.is_full());
+        assert!(!spool.is_blocking());
+        assert_eq!(sequence(spool.next_unsent().unwrap()), 1);
+    }
+
+    #[test]
+    fn test_acknowledge_past_dropped() {
+        let mut spool = spool("dropped", entry_size() * 2, "drop_newest");
+        for number in 1..=5 {
+            spool.push(number, &output(number), false).unwrap();
+        }
+        let mut sent = vec![];
+        while let Some(packet) = spool.next_unsent().unwrap() {
+            sent.push(packet.to_string());
+            spool.mark_sent();
+        }
+        assert_eq!(spool.dropped(), 3);
+        assert_eq!(sent.len(), 3);
+        assert_eq!(sent[2], "skipped (from = 3, to = 5)");
+
+        // output sent live is skipped too once the spool is full
+        spool.push(6, &output(6), true).unwrap();
+        assert_eq!(spool.len(), 3);
+        spool.push(7, &output(7), false).unwrap();
+        assert_eq!(skipped(spool.next_unsent().unwrap()), (3, 7));
+
+        spool.acknowledge(4).unwrap();
+        assert_eq!(skipped(spool.next_unsent().unwrap()), (5, 7));
+        spool.acknowledge(7).unwrap();
+        assert!(spool.is_empty());
+        spool.remove().unwrap();
+    }
+
+    #[test]
+    fn test_block() {
+        let mut spool = spool("block", entry_size() * 2, "block");
+        spool.push(1, &output(1), true).unwrap();
+        assert!(!spool.is_blocking());
+        spool.push(2, &output(2), true).unwrap();
+        assert!(spool.is_blocking());
+        spool.acknowledge(1).unwrap();
+        assert!(!spool.is_blocking());
+        assert_eq!(spool.dropped(), 0);
+    }
+
+    #[test]
+    fn test_compact() {
+        let mut spool = spool("compact", entry_size() * 2, "drop_oldest");
+        for number in 1..=5 {
+            spool.push(number, &output(number), false).unwrap();
+        }
+        assert!(fs::metadata(&spool.path).unwrap().len() <= entry_size() * 4);
+        assert_eq!(skipped(spool.next_unsent().unwrap()), (1, 3));
+        spool.mark_sent();
+        assert_eq!(sequence(spool.next_unsent().unwrap()), 4);
+        spool.mark_sent();
+        assert_eq!(sequence(spool.next_unsent().unwrap()), 5);
+    }
+}
@@ -0,0 +1,87 @@
+use std::time::Duration;
+
+use rand::Rng;
+
+/// The randomised delay range the server asks for when it closes the
+/// connection with `CloseCode::Restart`
+const RESTART_DELAY_SECS: (u64, u64) = (5, 30);
+
+/// Exponential backoff with jitter between reconnection attempts.
+#[derive(Debug)]
+pub struct Backoff {
+    initial: Duration,
+    max: Duration,
+    attempts: u32,
+}
+
+impl Backoff {
+    pub fn new(initial: Duration, max: Duration) -> Self {
+        Self {
+            initial,
+            max,
+            attempts: 0,
+        }
+    }
+
+    /// The delay before the next attempt. The delay doubles on every attempt
+    /// up to the maximum, and a random half of it is jittered away so that
+    /// clients do not reconnect in lockstep.
+    pub fn next_delay(&mut self) -> Duration {
+        let exponent = self.attempts.min(16);
+        let delay = self.initial.saturating_mul(1 << exponent).min(self.max);
+        self.attempts += 1;
+        let half = delay / 2;
+        half + rand::thread_rng().gen_range(Duration::ZERO..=half)
+    }
+
+    /// The delay before the next attempt after the server announced that it
+    /// is restarting
+    pub fn next_restart_delay(&mut self) -> Duration {
+        self.attempts += 1;
+        let (min, max) = RESTART_DELAY_SECS;
+        rand::thread_rng().gen_range(Duration::from_secs(min)..=Duration::from_secs(max))
+    }
+
+    /// The number of attempts made since the last reset
+    pub fn attempts(&self) -> u32 {
+        self.attempts
+    }
+
+    pub fn reset(&mut self) {
+        self.attempts = 0;
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_delay_grows_until_max() {
+        let mut backoff = Backoff::new(Duration::from_secs(1), Duration::from_secs(8));
+        let expected = [1, 2, 4, 8, 8, 8];
+        for seconds in expected {
+            let delay = backoff.next_delay();
+            let base = Duration::from_secs(seconds);
+            assert!(
+                delay >= base / 2 && delay <= base,
+                "{:?} for {:?}",
+                delay,
+                base
+            );
+        }
+        assert_eq!(backoff.attempts(), 6);
+
+        backoff.reset();
+        assert_eq!(backoff.attempts(), 0);
+        assert!(backoff.next_delay() <= Duration::from_secs(1));
+    }
+
+    #[test]
+    fn test_restart_delay() {
+        let mut backoff = Backoff::new(Duration::from_secs(1), Duration::from_secs(8));
+        let delay = backoff.next_restart_delay();
+        assert!(delay >= Duration::from_secs(5) && delay <= Duration::from_secs(30));
+        assert_eq!(backoff.attempts(), 1);
+    }
+}
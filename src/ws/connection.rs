@@ -0,0 +1,236 @@
+use std::time::Duration;
+
+use tokio::time::{timeout, Instant};
+
+use crate::config::configuration::{Heartbeat, Reconnect};
+use crate::error::ApplicationError;
+use crate::ws::{Backoff, CloseCode, Packet, WebSocket, WebSocketRequest};
+
+/// How long a single connection attempt may take before it is abandoned
+const CONNECT_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// A websocket to a session that is re-established, re-authenticated and
+/// resumed when the connection is lost.
+pub struct Connection {
+    request: WebSocketRequest,
+    token: String,
+    /// the token the server resumes the session with, from its last pong
+    reconnect_token: Option<String>,
+    heartbeat: Heartbeat,
+    max_attempts: u32,
+    backoff: Backoff,
+    websocket: Option<WebSocket>,
+    authenticated: bool,
+    resumed: bool,
+    close_code: Option<CloseCode>,
+    retry_at: Option<Instant>,
+}
+
+impl Connection {
+    /// Connects to the websocket and sends the authentication packet
+    pub async fn open(
+        request: WebSocketRequest,
+        token: impl Into<String>,
+        policy: &Reconnect,
+        heartbeat: &Heartbeat,
+    ) -> Result<Self, ApplicationError> {
+        let websocket = WebSocket::new(&request, heartbeat).await?;
+        let mut connection = Self {
+            request,
+            token: token.into(),
+            reconnect_token: None,
+            heartbeat: heartbeat.clone(),
+            max_attempts: policy.max_attempts(),
+            backoff: Backoff::new(policy.initial_delay(), policy.max_delay()),
+            websocket: Some(websocket),
+            authenticated: false,
+            resumed: false,
+            close_code: None,
+            retry_at: None,
+        };
+        connection.authenticate().await;
+        Ok(connection)
+    }
+
+    pub fn is_connected(&self) -> bool {
+        self.websocket.is_some()
+    }
+
+    pub fn is_authenticated(&self) -> bool {
+        self.authenticated
+    }
+
+    /// whether the connection was lost and another attempt is scheduled
+    pub fn is_reconnecting(&self) -> bool {
+        self.retry_at.is_some()
+    }
+
+    /// when the next reconnection attempt is due
+    pub fn retry_at(&self) -> Instant {
+        self.retry_at
+            .unwrap_or_else(|| Instant::now() + Duration::from_secs(86400))
+    }
+
+    /// get the next packet received, or `None` once the connection is lost
+    pub async fn next(&mut self) -> Option<Packet> {
+        let websocket = self.websocket.as_mut()?;
+        match websocket.next().await {
+            Ok(Some(packet)) => {
+                if let Packet::Pong(Some(token)) = &packet {
+                    self.reconnect_token = Some(token.clone());
+                }
+                Some(packet)
+            }
+            Ok(None) => {
+                let code = websocket.close_code();
+                self.lost(code);
+                None
+            }
+            Err(error) => {
+                warn!("websocket error: {}", error);
+                self.lost(None);
+                None
+            }
+        }
+    }
+
+    /// marks the connection as authenticated once the server accepted us,
+    /// announcing the reconnection when this connection replaces a lost one
+    pub async fn authenticated(&mut self) {
+        self.authenticated = true;
+        if self.resumed {
+            let attempts = self.backoff.attempts();
+            info!("reconnected after {} attempt(s)", attempts);
+            self.resumed = false;
+            self.backoff.reset();
+            self.send(&Packet::Reconnected { attempts }).await;
+        }
+    }
+
+    /// handles the server rejecting us, returning whether authentication is
+    /// retried: a reconnect token the server no longer knows is forgotten and
+    /// the token of the session is given instead
+    pub async fn authentication_failed(&mut self) -> bool {
+        if !self.resumed || self.reconnect_token.take().is_none() {
+            return false;
+        }
+        warn!("the server rejected the reconnect token, authenticating with the session token");
+        self.authenticate().await;
+        true
+    }
+
+    /// send a packet once authenticated, returning whether it was sent
+    pub async fn send(&mut self, packet: &Packet) -> bool {
+        if !self.authenticated {
+            return false;
+        }
+        let websocket = match self.websocket.as_mut() {
+            Some(websocket) => websocket,
+            None => return false,
+        };
+        match websocket.send(packet).await {
+            Ok(()) => true,
+            Err(error) => {
+                warn!("unable to send packet: {}", error);
+                self.lost(None);
+                false
+            }
+        }
+    }
+
+    /// answer a ping of the server
+    pub async fn pong(&mut self) {
+        if let Some(websocket) = self.websocket.as_mut() {
+            if let Err(error) = websocket.pong().await {
+                warn!("unable to send pong: {}", error);
+                self.lost(None);
+            }
+        }
+    }
+
+    /// makes one attempt at re-establishing the connection, scheduling the
+    /// next one when it fails. An error is returned once all attempts failed.
+    pub async fn reconnect(&mut self) -> Result<(), ApplicationError> {
+        let attempt = self.backoff.attempts();
+        info!("reconnecting to websocket (attempt {})", attempt);
+        let result = timeout(
+            CONNECT_TIMEOUT,
+            WebSocket::new(&self.request, &self.heartbeat),
+        )
+        .await
+        .unwrap_or_else(|_| Err(ApplicationError::transport("connection attempt timed out")));
+        match result {
+            Ok(websocket) => {
+                self.websocket = Some(websocket);
+                self.retry_at = None;
+                self.close_code = None;
+                self.resumed = true;
+                self.authenticate().await;
+                Ok(())
+            }
+            Err(error) => {
+                warn!("reconnect attempt {} failed: {}", attempt, error);
+                if attempt >= self.max_attempts {
+                    self.retry_at = None;
+                    return Err(ApplicationError::transport(format!(
+                        "unable to reconnect after {} attempts",
+                        attempt
+                    )));
+                }
+                self.schedule();
+                Ok(())
+            }
+        }
+    }
+
+    /// Gracefully close this connection.
+    pub async fn close(&mut self) -> Result<(), ApplicationError> {
+        self.retry_at = None;
+        self.authenticated = false;
+        if let Some(mut websocket) = self.websocket.take() {
+            websocket.close().await?;
+        }
+        Ok(())
+    }
+
+    /// authenticates with the token of the session, or with the reconnect
+    /// token once the connection was re-established so that the server
+    /// resumes the session
+    async fn authenticate(&mut self) {
+        let token = match &self.reconnect_token {
+            Some(token) if self.resumed => token.clone(),
+            _ => self.token.clone(),
+        };
+        if let Some(websocket) = self.websocket.as_mut() {
+            if let Err(error) = websocket.send(&Packet::Authenticate(token)).await {
+                warn!("unable to authenticate: {}", error);
+                self.lost(None);
+            }
+        }
+    }
+
+    fn lost(&mut self, code: Option<CloseCode>) {
+        self.websocket = None;
+        self.authenticated = false;
+        self.close_code = code;
+        match code {
+            Some(CloseCode::Normal) | Some(CloseCode::Policy) => {
+                info!("websocket closed by the server ({:?})", code);
+                self.retry_at = None;
+            }
+            _ => {
+                warn!("websocket connection lost ({:?})", code);
+                self.schedule();
+            }
+        }
+    }
+
+    fn schedule(&mut self) {
+        let delay = match self.close_code {
+            Some(CloseCode::Restart) => self.backoff.next_restart_delay(),
+            _ => self.backoff.next_delay(),
+        };
+        info!("reconnecting in {:.1}s", delay.as_secs_f32());
+        self.retry_at = Some(Instant::now() + delay);
+    }
+}
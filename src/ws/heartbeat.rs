@@ -1,49 +1,200 @@
+use std::future::Future;
 use std::pin::Pin;
 use std::task::{Context, Poll};
 use std::time::Duration;
 
 use futures_util::Stream;
-use tokio::time::{Instant, Interval};
+use tokio::time::{Instant, Sleep};
 
+use crate::config::configuration::Heartbeat as Policy;
+
+/// What the heartbeat asks of the connection
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum Beat {
+    /// a ping is due
+    Ping,
+    /// too many pings went unanswered, the connection is dead
+    Dead,
+}
+
+/// Paces the pings of a connection and notices when the pongs stop arriving.
+/// Its timer only wakes when a ping is due or a pong is overdue.
 pub struct Heartbeat {
-    timeout: u64,
-    last_heartbeat_at: Instant,
-    inner: Interval,
+    interval: Duration,
+    pong_timeout: Duration,
+    max_missed: u32,
+    timer: Pin<Box<Sleep>>,
+    next_ping_at: Instant,
+    /// when the ping waiting for a pong was sent
+    ping_sent_at: Option<Instant>,
+    last_pong_at: Option<Instant>,
+    /// pongs missed in a row
+    missed: u32,
+    latency: Latency,
     closed: bool,
 }
 
 impl Heartbeat {
-    pub fn new(timeout: u64) -> Self {
-        let interval = tokio::time::interval(Duration::from_millis(1));
+    pub fn new(policy: &Policy) -> Self {
+        let next_ping_at = Instant::now() + policy.interval();
         Self {
-            timeout,
-            last_heartbeat_at: Instant::now(),
-            inner: interval,
+            interval: policy.interval(),
+            pong_timeout: policy.pong_timeout(),
+            max_missed: policy.max_missed(),
+            timer: Box::pin(tokio::time::sleep_until(next_ping_at)),
+            next_ping_at,
+            ping_sent_at: None,
+            last_pong_at: None,
+            missed: 0,
+            latency: Latency::default(),
             closed: false,
         }
     }
 
+    /// records a pong, and the round trip of the ping it answers
+    pub fn pong(&mut self) {
+        let now = Instant::now();
+        if let Some(sent_at) = self.ping_sent_at.take() {
+            let latency = now - sent_at;
+            debug!("pong received after {}ms", latency.as_millis());
+            self.latency.record(latency);
+        }
+        self.last_pong_at = Some(now);
+        self.missed = 0;
+        self.schedule();
+    }
+
+    /// the round trips of the pings answered so far
+    pub fn latency(&self) -> &Latency {
+        &self.latency
+    }
+
     pub fn close(&mut self) {
         self.closed = true
     }
+
+    /// sets the timer to when a ping is due or the pong is overdue
+    fn schedule(&mut self) {
+        let deadline = match self.ping_sent_at {
+            Some(sent_at) => self.next_ping_at.min(sent_at + self.pong_timeout),
+            None => self.next_ping_at,
+        };
+        self.timer.as_mut().reset(deadline);
+    }
 }
 
 impl Stream for Heartbeat {
-    type Item = ();
+    type Item = Beat;
 
     fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
         if self.closed {
             return Poll::Ready(None);
         }
-        match self.inner.poll_tick(cx) {
-            Poll::Ready(instance) => {
-                if self.last_heartbeat_at.elapsed().as_secs() > self.timeout {
-                    self.last_heartbeat_at = instance;
-                    return Poll::Ready(Some(()));
+        if self.timer.as_mut().poll(cx).is_pending() {
+            return Poll::Pending;
+        }
+        let now = Instant::now();
+        if let Some(sent_at) = self.ping_sent_at {
+            if now >= sent_at + self.pong_timeout {
+                self.ping_sent_at = None;
+                self.missed += 1;
+                warn!(
+                    "no pong received within {}s ({} missed)",
+                    self.pong_timeout.as_secs(),
+                    self.missed
+                );
+                if self.missed >= self.max_missed {
+                    match self.last_pong_at {
+                        Some(at) => warn!("last pong received {}s ago", (now - at).as_secs()),
+                        None => warn!("no pong ever received"),
+                    }
+                    self.closed = true;
+                    return Poll::Ready(Some(Beat::Dead));
                 }
-                Poll::Pending
             }
-            Poll::Pending => Poll::Pending,
         }
+        let beat = if now >= self.next_ping_at {
+            self.next_ping_at = now + self.interval;
+            // a ping still waiting keeps its own deadline
+            self.ping_sent_at.get_or_insert(now);
+            Some(Beat::Ping)
+        } else {
+            None
+        };
+        self.schedule();
+        match beat {
+            Some(beat) => Poll::Ready(Some(beat)),
+            // the timer was reset, so polling it registers the new deadline
+            None => self.poll_next(cx),
+        }
+    }
+}
+
+/// The round trips of the pings of a connection
+#[derive(Debug, Default)]
+pub struct Latency {
+    last: Option<Duration>,
+    total: Duration,
+    count: u32,
+}
+
+impl Latency {
+    fn record(&mut self, latency: Duration) {
+        self.last = Some(latency);
+        self.total += latency;
+        self.count += 1;
+    }
+
+    pub fn last(&self) -> Option<Duration> {
+        self.last
+    }
+
+    pub fn average(&self) -> Option<Duration> {
+        if self.count == 0 {
+            return None;
+        }
+        Some(self.total / self.count)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use tokio_stream::StreamExt;
+
+    use super::*;
+
+    fn policy() -> Policy {
+        serde_yaml::from_str("{interval: 5, pong_timeout: 2, max_missed: 2}").unwrap()
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_pings_until_dead() {
+        let mut heartbeat = Heartbeat::new(&policy());
+        let start = Instant::now();
+
+        assert_eq!(heartbeat.next().await, Some(Beat::Ping));
+        assert_eq!(start.elapsed(), Duration::from_secs(5));
+        assert_eq!(heartbeat.next().await, Some(Beat::Ping));
+        assert_eq!(start.elapsed(), Duration::from_secs(10));
+        assert_eq!(heartbeat.next().await, Some(Beat::Dead));
+        assert_eq!(start.elapsed(), Duration::from_secs(12));
+        assert_eq!(heartbeat.next().await, None);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_pongs_keep_alive() {
+        let mut heartbeat = Heartbeat::new(&policy());
+
+        for _ in 0..5 {
+            assert_eq!(heartbeat.next().await, Some(Beat::Ping));
+            tokio::time::sleep(Duration::from_millis(100)).await;
+            heartbeat.pong();
+        }
+
+        assert_eq!(heartbeat.latency().last(), Some(Duration::from_millis(100)));
+        assert_eq!(
+            heartbeat.latency().average(),
+            Some(Duration::from_millis(100))
+        );
     }
 }
@@ -1,10 +1,14 @@
-pub use heartbeat::Heartbeat;
-pub use message::Message;
-pub use packet::Packet;
+pub use backoff::Backoff;
+pub use connection::Connection;
+pub use heartbeat::{Beat, Heartbeat};
+pub use message::{CloseCode, Message};
+pub use packet::{Encoding, Packet, Termination, TerminationReason};
 pub use stream::WebSocketStream;
 pub use transport::Transport;
 pub use websocket::{WebSocket, WebSocketRequest};
 
+mod backoff;
+mod connection;
 mod heartbeat;
 mod message;
 mod packet;
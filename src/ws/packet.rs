@@ -1,16 +1,61 @@
 use std::fmt;
 
+use chrono::{DateTime, Utc};
 use serde::{Deserialize, Serialize};
 
+use crate::cmd::Process;
+
+/// How the message of a command output packet is encoded
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Encoding {
+    /// the output is valid UTF-8 and sent as is
+    #[default]
+    Utf8,
+    /// the output is not valid UTF-8, its bytes are base64 encoded
+    Base64,
+}
+
+impl Encoding {
+    pub fn is_utf8(&self) -> bool {
+        *self == Encoding::Utf8
+    }
+}
+
+/// Why tracer stopped the command
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum TerminationReason {
+    /// the server sent a terminate packet
+    Server,
+    /// tracer received SIGINT, SIGTERM or SIGQUIT
+    LocalSignal,
+    /// the command ran for longer than its timeout
+    Timeout,
+    /// the command did not write any output for longer than its idle timeout
+    Idle,
+}
+
+/// How tracer stopped the command: the signal it sent last, and whether it
+/// had to kill the command after the grace period
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Termination {
+    pub reason: TerminationReason,
+    pub signal: String,
+    pub escalated: bool,
+}
+
 #[derive(Debug, Clone, Serialize, Deserialize)]
 #[serde(tag = "packet", content = "content")]
 pub enum Packet {
-    // send ping packet
+    // send ping packet on every heartbeat, or receive one from the server
+    // which is answered with a pong
     #[serde(rename = "ping")]
     Ping,
-    // receive pong packet with a reconnect token
+    // answer a ping, or receive the answer to ours with the token the server
+    // resumes the session with once the connection is re-established
     #[serde(rename = "pong")]
-    Pong,
+    Pong(Option<String>),
     //  send authentication packet
     #[serde(rename = "authenticate")]
     Authenticate(String),
@@ -20,42 +65,145 @@ pub enum Packet {
     //  received authentication failed
     #[serde(rename = "authentication_failed")]
     AuthenticationFailed(String),
-    //  send command output i.e. stdout, stderr
+    //  send command output i.e. stdout, stderr, or tty when both are merged
+    //  into a pseudo-terminal, with when it was written and the microseconds
+    //  elapsed since the command was launched
     #[serde(rename = "command_output")]
-    CommandOutput { stream: String, message: String },
+    CommandOutput {
+        sequence: u64,
+        stream: String,
+        message: String,
+        #[serde(default, skip_serializing_if = "Encoding::is_utf8")]
+        encoding: Encoding,
+        timestamp: DateTime<Utc>,
+        offset_us: u64,
+    },
+    //  send the range of sequence numbers of the output dropped because the
+    //  spool was full, which will never be sent
+    #[serde(rename = "skipped")]
+    Skipped { from: u64, to: u64 },
+    //  receive the highest contiguous sequence number the server persisted
+    #[serde(rename = "ack")]
+    Ack(u64),
     //  send command terminated
     #[serde(rename = "command_terminated")]
-    CommandTerminated(u32),
+    CommandTerminated {
+        code: Option<i32>,
+        signal: Option<i32>,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        termination: Option<Termination>,
+        //  processes the command left running, which tracer stopped
+        #[serde(default, skip_serializing_if = "Vec::is_empty")]
+        lingering: Vec<Process>,
+    },
+    //  receive the new window size of the viewer's terminal
+    #[serde(rename = "resize")]
+    Resize { columns: u16, rows: u16 },
+    //  receive input for the command, encoded like the output, and whether
+    //  the input ends there
+    #[serde(rename = "command_input")]
+    CommandInput {
+        #[serde(default)]
+        message: String,
+        #[serde(default)]
+        encoding: Encoding,
+        #[serde(default)]
+        eof: bool,
+    },
     //  receive command terminate packet
     #[serde(rename = "command_terminate")]
     CommandTerminate,
     //  send command launched
     #[serde(rename = "command_launched")]
     CommandLaunched,
+    //  send reconnected after the connection was re-established
+    #[serde(rename = "reconnected")]
+    Reconnected { attempts: u32 },
 }
 
 impl fmt::Display for Packet {
     fn fmt(&self, formatter: &mut fmt::Formatter<'_>) -> fmt::Result {
         match &self {
             Packet::Ping => write!(formatter, "ping"),
-            Packet::Pong => write!(formatter, "pong"),
+            Packet::Pong(_) => write!(formatter, "pong"),
             Packet::Authenticate(_) => write!(formatter, "authenticate"),
             Packet::AuthenticationSuccessful => write!(formatter, "authentication successful"),
             Packet::AuthenticationFailed(message) => {
                 write!(formatter, "authenticate failed (message = {})", message)
             }
-            Packet::CommandOutput { stream, message } => {
+            Packet::CommandOutput {
+                sequence,
+                stream,
+                message,
+                ..
+            } => {
+                write!(
+                    formatter,
+                    "command output (sequence = {}, stream = {}, message = {})",
+                    sequence, stream, message
+                )
+            }
+            Packet::Skipped { from, to } => {
+                write!(formatter, "skipped (from = {}, to = {})", from, to)
+            }
+            Packet::Ack(sequence) => write!(formatter, "ack (sequence = {})", sequence),
+            Packet::CommandTerminated { code, signal, .. } => {
                 write!(
                     formatter,
-                    "command output ( stream = {}, message = {})",
-                    stream, message
+                    "command terminated (code = {:?}, signal = {:?})",
+                    code, signal
                 )
             }
-            Packet::CommandTerminated(code) => {
-                write!(formatter, "command terminated (code = {})", code)
+            Packet::Resize { columns, rows } => {
+                write!(formatter, "resize (columns = {}, rows = {})", columns, rows)
+            }
+            Packet::CommandInput { message, eof, .. } => {
+                write!(
+                    formatter,
+                    "command input (message = {}, eof = {})",
+                    message, eof
+                )
             }
             Packet::CommandTerminate => write!(formatter, "command terminate"),
             Packet::CommandLaunched => write!(formatter, "command launched"),
+            Packet::Reconnected { attempts } => {
+                write!(formatter, "reconnected (attempts = {})", attempts)
+            }
         }
     }
 }
+
+#[cfg(test)]
+mod test {
+    use chrono::TimeZone;
+
+    use super::*;
+
+    #[test]
+    fn test_encode_command_output() {
+        let packet = Packet::CommandOutput {
+            sequence: 7,
+            stream: "stdout".to_string(),
+            message: "hello".to_string(),
+            encoding: Encoding::Utf8,
+            timestamp: Utc.ymd(2022, 1, 15).and_hms_micro(10, 30, 0, 250),
+            offset_us: 1500,
+        };
+        assert_eq!(
+            serde_json::to_string(&packet).unwrap(),
+            r#"{"packet":"command_output","content":{"sequence":7,"stream":"stdout","message":"hello","timestamp":"2022-01-15T10:30:00.000250Z","offset_us":1500}}"#
+        );
+    }
+
+    #[test]
+    fn test_decode_pong() {
+        let pong = serde_json::from_str::<Packet>(r#"{"packet":"pong"}"#).unwrap();
+        let resumable =
+            serde_json::from_str::<Packet>(r#"{"packet":"pong","content":"reconnect token"}"#)
+                .unwrap();
+
+        assert!(matches!(pong, Packet::Pong(None)));
+        assert!(matches!(&resumable, Packet::Pong(Some(token)) if token == "reconnect token"));
+        assert_eq!(resumable.to_string(), "pong");
+    }
+}
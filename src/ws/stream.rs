@@ -6,10 +6,11 @@ use futures_util::sink::SinkExt;
 use futures_util::stream::StreamExt;
 use futures_util::Sink;
 use tokio::net::TcpStream;
+use tokio_tungstenite::tungstenite::protocol::CloseFrame;
 use tokio_tungstenite::MaybeTlsStream;
 
 use crate::error::ApplicationError;
-use crate::ws::Message;
+use crate::ws::{CloseCode, Message};
 
 pub struct WebSocketStream {
     inner: tokio_tungstenite::WebSocketStream<MaybeTlsStream<TcpStream>>,
@@ -20,6 +21,7 @@ impl WebSocketStream {
         Self { inner }
     }
 
+    #[allow(dead_code)]
     pub async fn recv(&mut self) -> Option<Result<Message, ApplicationError>> {
         self.next().await
     }
@@ -31,9 +33,16 @@ impl WebSocketStream {
             .map_err(|error| error.into())
     }
 
-    /// Gracefully close this WebSocket.
+    /// Gracefully close this WebSocket with a normal closure.
     pub async fn close(&mut self) -> Result<(), ApplicationError> {
-        self.inner.close(None).await.map_err(|error| error.into())
+        let frame = CloseFrame {
+            code: CloseCode::Normal.into(),
+            reason: "".into(),
+        };
+        self.inner
+            .close(Some(frame))
+            .await
+            .map_err(|error| error.into())
     }
 }
 
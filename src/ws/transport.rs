@@ -9,16 +9,10 @@ impl Transport {
     }
 
     pub fn decode(&self, json: &str) -> Option<Packet> {
-        match serde_json::from_str::<Packet>(json) {
-            Ok(packet) => Some(packet),
-            Err(_) => None,
-        }
+        serde_json::from_str::<Packet>(json).ok()
     }
 
-    pub fn encode(&self, packet: Packet) -> Option<String> {
-        match serde_json::to_string(&packet) {
-            Ok(json) => Some(json),
-            Err(_) => None,
-        }
+    pub fn encode(&self, packet: &Packet) -> Option<String> {
+        serde_json::to_string(packet).ok()
     }
 }
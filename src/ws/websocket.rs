@@ -1,14 +1,12 @@
-use futures::{Stream, TryStream};
+use futures_util::SinkExt;
 use tokio_stream::StreamExt;
 use tokio_tungstenite::connect_async;
-use tokio_tungstenite::tungstenite::client::IntoClientRequest;
 use tungstenite::http::Request;
-use tungstenite::protocol::WebSocketConfig;
-use url::Url;
 
+use crate::config::configuration::Heartbeat as HeartbeatPolicy;
 use crate::error::ApplicationError;
 use crate::http::user_agent;
-use crate::ws::{Heartbeat, Message, Packet, Transport, WebSocketStream};
+use crate::ws::{Beat, CloseCode, Heartbeat, Message, Packet, Transport, WebSocketStream};
 
 pub trait RequestBuilderExt {
     fn auth(self, token: &str) -> Self;
@@ -35,6 +33,7 @@ pub struct WebSocket {
     inner: WebSocketStream,
     transport: Transport,
     heartbeat: Heartbeat,
+    close_code: Option<CloseCode>,
 }
 
 pub struct WebSocketRequest {
@@ -43,16 +42,19 @@ pub struct WebSocketRequest {
 }
 
 impl WebSocketRequest {
-    pub fn new(
-        url: impl AsRef<str>,
-        token: impl AsRef<str>,
-    ) -> Result<Request<()>, ApplicationError> {
-        let uri = url.as_ref();
-        let token = token.as_ref();
+    pub fn new(url: impl AsRef<str>, token: impl AsRef<str>) -> Self {
+        Self {
+            url: url.as_ref().to_string(),
+            token: token.as_ref().to_string(),
+        }
+    }
+
+    /// build the http request used for the websocket handshake
+    pub fn build(&self) -> Result<Request<()>, ApplicationError> {
         let agent = user_agent();
         let request = Request::builder()
-            .uri(uri)
-            .auth(token)
+            .uri(&self.url)
+            .auth(&self.token)
             .agent(&agent)
             .subprotocol("tracer")
             .body(())?;
@@ -61,35 +63,70 @@ impl WebSocketRequest {
 }
 
 impl WebSocket {
-    pub async fn new(request: Request<()>) -> Result<Self, ApplicationError> {
+    pub async fn new(
+        request: &WebSocketRequest,
+        heartbeat: &HeartbeatPolicy,
+    ) -> Result<Self, ApplicationError> {
         info!("Connecting to websocket");
 
-        let (stream, _) = connect_async(request).await?;
-        let timeout_in_secs = 2;
+        let (stream, _) = connect_async(request.build()?).await?;
         let inner = WebSocketStream::new(stream);
-        let heartbeat = Heartbeat::new(timeout_in_secs);
+        let heartbeat = Heartbeat::new(heartbeat);
         let transport = Transport::new();
 
         Ok(Self {
             inner,
             transport,
             heartbeat,
+            close_code: None,
         })
     }
 
-    /// get the next message received on the websocket
+    /// get the next packet received on the websocket, or `None` once the
+    /// connection has been closed. A ping is sent on every heartbeat, and
+    /// the connection is deemed lost once the pongs stop arriving.
     pub async fn next(&mut self) -> Result<Option<Packet>, ApplicationError> {
-        tokio::select! {
-            Some(_) = self.heartbeat.next() => Ok(Some(Packet::Ping)),
-            Some(Ok(Message::Text(message))) = self.inner.next() => {
-                let decoded = self.transport.decode(&message);
-                Ok(decoded)
-            }  else => Ok(None)
+        loop {
+            tokio::select! {
+                Some(beat) = self.heartbeat.next() => match beat {
+                    Beat::Ping => self.ping().await?,
+                    Beat::Dead => {
+                        return Err(ApplicationError::transport(
+                            "the connection is dead, the server stopped answering pings",
+                        ))
+                    }
+                },
+                message = self.inner.next() => match message {
+                    Some(Ok(Message::Text(message))) => match self.transport.decode(&message) {
+                        Some(packet @ Packet::Pong(_)) => {
+                            self.heartbeat.pong();
+                            return Ok(Some(packet));
+                        }
+                        Some(packet) => return Ok(Some(packet)),
+                        None => debug!("ignoring undecodable message: {}", message),
+                    },
+                    Some(Ok(Message::Ping(_))) => {
+                        // the pong is queued by tungstenite, flushing sends it
+                        // without waiting for our next packet
+                        debug!("websocket ping received");
+                        self.inner.flush().await?;
+                    }
+                    Some(Ok(Message::Pong(_))) => self.heartbeat.pong(),
+                    Some(Ok(Message::Close(frame))) => {
+                        debug!("websocket closed by server: {:?}", frame);
+                        self.close_code = frame.map(|(code, _)| code);
+                        return Ok(None);
+                    }
+                    Some(Ok(_)) => continue,
+                    Some(Err(error)) => return Err(error),
+                    None => return Ok(None),
+                }
+            }
         }
     }
 
     /// send a packet
-    pub async fn send(&mut self, packet: Packet) -> Result<(), ApplicationError> {
+    pub async fn send(&mut self, packet: &Packet) -> Result<(), ApplicationError> {
         debug!("sending packet: {}", packet);
         if let Some(data) = self.transport.encode(packet) {
             self.inner.send(Message::text(data)).await?
@@ -97,13 +134,31 @@ impl WebSocket {
         Ok(())
     }
 
+    /// the code the server gave when it closed the connection, if any
+    pub fn close_code(&self) -> Option<CloseCode> {
+        self.close_code
+    }
+
     /// send a ping request
-    pub async fn ping(&mut self) -> Result<(), ApplicationError> {
-        self.send(Packet::Ping).await
+    async fn ping(&mut self) -> Result<(), ApplicationError> {
+        self.send(&Packet::Ping).await
+    }
+
+    /// answer a ping of the server
+    pub async fn pong(&mut self) -> Result<(), ApplicationError> {
+        self.send(&Packet::Pong(None)).await
     }
 
     /// Gracefully close this WebSocket.
     pub async fn close(&mut self) -> Result<(), ApplicationError> {
+        let latency = self.heartbeat.latency();
+        if let (Some(last), Some(average)) = (latency.last(), latency.average()) {
+            debug!(
+                "round trip to the server: {}ms, {}ms on average",
+                last.as_millis(),
+                average.as_millis()
+            );
+        }
         self.inner.close().await?;
         self.heartbeat.close();
         Ok(())